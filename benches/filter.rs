@@ -0,0 +1,128 @@
+//! Benchmarks of filtering synthetic chat logs. Sizes of the logs are set by the comma-separated SS13_BENCH_MESSAGES
+//! variable, e.g. `SS13_BENCH_MESSAGES=1000,100000 cargo bench`.
+
+use std::{env, hint::black_box};
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use ss13_mlogfilter::{Config, ConfigArgs, MatchField, filter_chat_log, synthetic};
+
+/// Numbers of messages in the generated logs, unless set by SS13_BENCH_MESSAGES
+const MESSAGE_COUNTS: [usize; 2] = [1_000, 10_000];
+
+/// Seed of the generated logs, so that every run filters the same ones
+const SEED: u64 = 42;
+
+fn message_counts() -> Vec<usize> {
+    match env::var("SS13_BENCH_MESSAGES") {
+        Ok(counts) => counts
+            .split(',')
+            .map(|count| {
+                count
+                    .trim()
+                    .parse()
+                    .expect("SS13_BENCH_MESSAGES must be a list of numbers")
+            })
+            .collect(),
+        Err(_) => MESSAGE_COUNTS.to_vec(),
+    }
+}
+
+/// Configs the logs are filtered with, named after their patterns
+fn pattern_sets() -> Vec<(&'static str, Config)> {
+    let config = |args: ConfigArgs| Config::from_args(args).unwrap();
+    let patterns = |patterns: &[&str]| patterns.iter().map(|pattern| pattern.to_string()).collect();
+    vec![
+        (
+            "single_substring",
+            config(ConfigArgs {
+                include: patterns(&["nuke"]),
+                ..Default::default()
+            }),
+        ),
+        (
+            "many_substrings",
+            config(ConfigArgs {
+                include: patterns(&[
+                    "nuke",
+                    "breach",
+                    "singularity",
+                    "traitor",
+                    "shuttle",
+                    "plasma",
+                    "toolbox",
+                    "doctor",
+                    "bridge",
+                    "security",
+                    "evacuate",
+                    "medbay",
+                    "cargo",
+                    "bar",
+                    "east wing",
+                    "café",
+                ]),
+                exclude: patterns(&["lol"]),
+                ..Default::default()
+            }),
+        ),
+        (
+            "regex",
+            config(ConfigArgs {
+                regex: true,
+                include: patterns(&[r"\bnu+ke\b", r"breach(es)?\s+in\s+\w+"]),
+                ..Default::default()
+            }),
+        ),
+        (
+            "visible_text",
+            config(ConfigArgs {
+                include: patterns(&["nuke", "breach"]),
+                match_field: Some(MatchField::Text),
+                ..Default::default()
+            }),
+        ),
+    ]
+}
+
+fn bench_filter_chat_log(criterion: &mut Criterion) {
+    let mut group = criterion.benchmark_group("filter_chat_log");
+    for count in message_counts() {
+        let chat_log = synthetic::chat_log(count, SEED);
+        group.throughput(Throughput::Bytes(chat_log.len() as u64));
+        for (name, config) in pattern_sets() {
+            group.bench_with_input(
+                BenchmarkId::new(name, count),
+                &chat_log,
+                |bencher, chat_log| {
+                    bencher.iter(|| filter_chat_log(black_box(chat_log), &config).unwrap())
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+fn bench_config_matches(criterion: &mut Criterion) {
+    let mut group = criterion.benchmark_group("Config::matches");
+    for count in message_counts() {
+        let messages = synthetic::chat_messages(count, SEED);
+        group.throughput(Throughput::Elements(count as u64));
+        for (name, config) in pattern_sets() {
+            group.bench_with_input(
+                BenchmarkId::new(name, count),
+                &messages,
+                |bencher, messages| {
+                    bencher.iter(|| {
+                        messages
+                            .iter()
+                            .filter(|message| config.matches(black_box(message)).unwrap())
+                            .count()
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_filter_chat_log, bench_config_matches);
+criterion_main!(benches);
@@ -0,0 +1,136 @@
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use crate::parsing;
+
+/// Number of pseudonyms, which seeded ones are picked from. Collisions are rare below a few hundred speakers.
+const SEEDED_PSEUDONYMS: u64 = 10_000;
+
+/// Replaces senders of messages with pseudonyms like "Player1", so that every sender gets the same pseudonym in all
+/// messages anonymized by the same `Anonymizer`. Pseudonyms are numbered in the order of first appearance, unless a
+/// seed is given, in which case the number is picked from the seed and the sender's name, so the same seed gives the
+/// same pseudonyms regardless of the order logs are processed in. It can be shared between threads.
+#[derive(Debug, Default)]
+pub struct Anonymizer {
+    seed: Option<u64>,
+    pseudonyms: Mutex<Pseudonyms>,
+}
+
+#[derive(Debug, Default)]
+struct Pseudonyms {
+    /// Number of the pseudonym of each sender
+    by_sender: HashMap<String, u64>,
+    taken: HashSet<u64>,
+}
+
+impl Anonymizer {
+    pub fn new(seed: Option<u64>) -> Self {
+        Self {
+            seed,
+            pseudonyms: Mutex::default(),
+        }
+    }
+
+    /// Replaces the name of the message's sender, as found by `message_sender`, with the sender's pseudonym. Messages
+    /// without a sender are unchanged, as are names mentioned elsewhere in the message.
+    pub fn anonymize<'m>(&self, message: &'m str) -> Cow<'m, str> {
+        let Some(range) = parsing::sender_range(message) else {
+            return Cow::Borrowed(message);
+        };
+        let sender = parsing::plain_text(&message[range.clone()]);
+        if sender.is_empty() {
+            return Cow::Borrowed(message);
+        }
+        let mut anonymized = message.to_string();
+        anonymized.replace_range(range, &self.pseudonym(&sender));
+        Cow::Owned(anonymized)
+    }
+
+    /// Returns the pseudonym of `sender`, picking a new one if the sender wasn't seen before
+    pub fn pseudonym(&self, sender: &str) -> String {
+        let mut pseudonyms = self.pseudonyms.lock().unwrap();
+        if let Some(number) = pseudonyms.by_sender.get(sender) {
+            return format!("Player{}", number);
+        }
+        let mut number = match self.seed {
+            Some(seed) => seeded_hash(seed, sender) % SEEDED_PSEUDONYMS + 1,
+            None => pseudonyms.taken.len() as u64 + 1,
+        };
+        while pseudonyms.taken.contains(&number) {
+            number += 1;
+        }
+        pseudonyms.taken.insert(number);
+        pseudonyms.by_sender.insert(sender.to_string(), number);
+        format!("Player{}", number)
+    }
+
+    /// Returns the senders seen so far along with their pseudonyms, ordered by the pseudonyms
+    pub fn mapping(&self) -> Vec<(String, String)> {
+        let pseudonyms = self.pseudonyms.lock().unwrap();
+        let mut mapping: Vec<_> = pseudonyms.by_sender.iter().collect();
+        mapping.sort_by_key(|(_, number)| **number);
+        mapping
+            .into_iter()
+            .map(|(sender, number)| (sender.clone(), format!("Player{}", number)))
+            .collect()
+    }
+}
+
+/// FNV-1a hash of the seed followed by the text, which unlike the standard hasher is the same in every build
+fn seeded_hash(seed: u64, text: &str) -> u64 {
+    seed.to_le_bytes()
+        .iter()
+        .chain(text.as_bytes())
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn senders_keep_their_pseudonyms() {
+        let anonymizer = Anonymizer::new(None);
+        let said = |message: &str| anonymizer.anonymize(message).into_owned();
+        assert_eq!(
+            said(
+                "<div class=\"ChatMessage\"><span class=\"timestamp\">[12:00]</span> <b>John &lt;3</b> says, \"Jane?\"</div>"
+            ),
+            "<div class=\"ChatMessage\"><span class=\"timestamp\">[12:00]</span> <b>Player1</b> says, \"Jane?\"</div>"
+        );
+        assert_eq!(
+            said("<div class=\"ChatMessage\"><span class=\"name\">Jane</span> waves.</div>"),
+            "<div class=\"ChatMessage\"><span class=\"name\">Player2</span> waves.</div>"
+        );
+        assert_eq!(
+            said("<div class=\"ChatMessage\"><b>John &lt;3</b> nods.</div>"),
+            "<div class=\"ChatMessage\"><b>Player1</b> nods.</div>"
+        );
+        // radio and system messages have no sender
+        let radio = "<div class=\"ChatMessage\">[Common] <b>John &lt;3</b> says, \"Hi\"</div>";
+        assert_eq!(said(radio), radio);
+        assert_eq!(
+            anonymizer.mapping(),
+            [
+                ("John <3".to_string(), "Player1".to_string()),
+                ("Jane".to_string(), "Player2".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn seeded_pseudonyms_dont_depend_on_order() {
+        let (first, second) = (Anonymizer::new(Some(7)), Anonymizer::new(Some(7)));
+        let john = first.pseudonym("John");
+        let jane = first.pseudonym("Jane");
+        assert_eq!(second.pseudonym("Jane"), jane);
+        assert_eq!(second.pseudonym("John"), john);
+        assert_ne!(john, jane);
+        assert_ne!(Anonymizer::new(Some(8)).pseudonym("John"), john);
+    }
+}
@@ -0,0 +1,166 @@
+use std::{
+    fs::{File, OpenOptions, create_dir_all},
+    io::{self, Write},
+    path::{Component, Path, PathBuf},
+    time::SystemTime,
+};
+
+use flate2::{Compression, write::GzEncoder};
+use tar::{Builder, Header};
+
+/// Underlying writer of the tar archive, optionally gzipped
+enum ArchiveWriter {
+    Plain(File),
+    Gzip(GzEncoder<File>),
+}
+
+impl Write for ArchiveWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            ArchiveWriter::Plain(file) => file.write(buf),
+            ArchiveWriter::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            ArchiveWriter::Plain(file) => file.flush(),
+            ArchiveWriter::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+impl ArchiveWriter {
+    fn finish(self) -> io::Result<()> {
+        match self {
+            ArchiveWriter::Plain(mut file) => file.flush(),
+            ArchiveWriter::Gzip(encoder) => encoder.finish()?.flush(),
+        }
+    }
+}
+
+/// Tar archive, which collects filtered logs as entries instead of loose files
+pub struct TarOutput {
+    builder: Builder<ArchiveWriter>,
+}
+
+impl TarOutput {
+    /// Creates the archive at `path` along with missing parent directories. Archive is gzipped if `path` ends with
+    /// `.gz` or `.tgz`.
+    pub fn create<T: AsRef<Path>>(path: T, overwrite: bool) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        if let Some(parent_dir) = path.parent() {
+            create_dir_all(parent_dir).map_err(|err| {
+                anyhow::format_err!(
+                    "failed to create parent directories for the archive {}: {}",
+                    path.to_string_lossy(),
+                    err
+                )
+            })?;
+        }
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(!overwrite)
+            .create(overwrite)
+            .truncate(overwrite)
+            .open(path)
+            .map_err(|err| {
+                anyhow::format_err!(
+                    "failed to create the archive {}: {}",
+                    path.to_string_lossy(),
+                    err
+                )
+            })?;
+
+        let gzip = path
+            .extension()
+            .is_some_and(|extension| extension == "gz" || extension == "tgz");
+        let writer = if gzip {
+            ArchiveWriter::Gzip(GzEncoder::new(file, Compression::default()))
+        } else {
+            ArchiveWriter::Plain(file)
+        };
+
+        Ok(Self {
+            builder: Builder::new(writer),
+        })
+    }
+
+    /// Appends `contents` as an entry named by `entry_name`, dated with the current time
+    pub fn append<T: AsRef<Path>>(&mut self, entry_path: T, contents: &str) -> anyhow::Result<()> {
+        let entry_path = entry_name(entry_path);
+        let modified = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs())
+            .unwrap_or_default();
+
+        let mut header = Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(modified);
+        header.set_cksum();
+
+        self.builder
+            .append_data(&mut header, &entry_path, contents.as_bytes())
+            .map_err(|err| {
+                anyhow::format_err!(
+                    "failed to append {} to the archive: {}",
+                    entry_path.to_string_lossy(),
+                    err
+                )
+            })
+    }
+
+    /// Writes the archive trailer and flushes the archive to disk
+    pub fn finish(self) -> anyhow::Result<()> {
+        self.builder
+            .into_inner()
+            .and_then(ArchiveWriter::finish)
+            .map_err(|err| anyhow::format_err!("failed to finish the archive: {}", err))
+    }
+}
+
+/// Name of the entry for the output at `output_path`. Root, `.` and `..` components are dropped, since tar entries must
+/// be relative.
+pub fn entry_name<T: AsRef<Path>>(output_path: T) -> PathBuf {
+    output_path
+        .as_ref()
+        .components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tar::Archive;
+
+    use super::*;
+    use crate::test_files::TestFiles;
+
+    #[test]
+    fn entries_are_dated_and_relative() {
+        let files = TestFiles::new();
+        let tar_path = files.path().join("missing").join("logs.tar");
+        let mut tar = TarOutput::create(&tar_path, false).unwrap();
+        tar.append("./out/filtered_game.html", "nuke").unwrap();
+        tar.finish().unwrap();
+
+        let mut archive = Archive::new(fs::File::open(&tar_path).unwrap());
+        let entries: Vec<(PathBuf, u64)> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                (
+                    entry.path().unwrap().into_owned(),
+                    entry.header().mtime().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].0, Path::new("out").join("filtered_game.html"));
+        assert!(entries[0].1 > 0);
+    }
+}
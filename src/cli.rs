@@ -0,0 +1,666 @@
+use std::path::{Path, PathBuf};
+
+use clap::{ArgGroup, Parser, ValueEnum};
+use glob::Pattern;
+
+use crate::{
+    BuiltinParser, Config, ConfigArgs, IncludeMode, MatchField, OutputFormat, Phase, Timestamp,
+};
+
+/// Simple CLI utility to filter the Space Station 13 saved chat logs
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+#[command(group(
+    ArgGroup::new("counting")
+        .args(["count", "count_messages", "count_lines"])
+        .multiple(true)
+        .conflicts_with_all(["outputs", "out_dir", "tar", "stdout", "gzip_output"])
+))]
+pub struct Cli {
+    /// Paths to chat log files to filter. Paths containing "*", "?" or "[" are expanded as glob patterns, e.g.
+    /// "logs/round-*/game.html". Directories are searched for logs matching --file-glob. Gzipped logs are
+    /// decompressed before filtering.
+    #[arg(short, long, value_name = "FILES")]
+    pub paths: Vec<PathBuf>,
+
+    /// Paths to the output files. Defaults to "{out_dir}/filtered_{INPUT FILE NAME}". out_dir defaults to current
+    /// working directory the program's working directory. Missing directories in the path will be created recursively.
+    /// If more paths than outputs were provided, missing outputs will be set to default. If more outputs than paths
+    /// were provided, excessive outputs will be ignored.
+    #[arg(short, long, value_name = "FILES")]
+    pub outputs: Vec<PathBuf>,
+
+    /// Path to the directory, which will be considered base for default outputs. Missing directories in the path will
+    /// be created recursively.
+    #[arg(long, value_name = "DIR")]
+    pub out_dir: Option<PathBuf>,
+
+    /// Write filtered logs to the standard output instead of files, concatenated in the order of inputs. Progress is
+    /// printed to the standard error.
+    #[arg(long, conflicts_with_all = ["outputs", "out_dir", "tar"])]
+    pub stdout: bool,
+
+    /// Only count kept and dropped messages of each log along with the lines of its output, without writing any outputs
+    #[arg(long)]
+    pub count: bool,
+
+    /// Like --count, but report only the kept and dropped messages. A single message may span many lines.
+    #[arg(long)]
+    pub count_messages: bool,
+
+    /// Like --count, but report only the line breaks in the output each log would be filtered into
+    #[arg(long)]
+    pub count_lines: bool,
+
+    /// Merge all logs into this single output instead of filtering each into its own one. Messages of the logs go in
+    /// the order of inputs in a single chat pane, with the header of the first log and the footer of the last one.
+    /// --outputs and --out-dir are ignored.
+    #[arg(long, value_name = "OUTPUT", conflicts_with_all = ["stdout", "counting", "tar"])]
+    pub merge: Option<PathBuf>,
+
+    /// Write the source log and the position in it of each message of the merged output into this file, as JSON if
+    /// its extension is ".json" and as CSV otherwise. Messages and positions are numbered from 1.
+    #[arg(long, value_name = "FILE", requires = "merge")]
+    pub merge_index: Option<PathBuf>,
+
+    /// Gzip the filtered logs. ".gz" is appended to the default output names.
+    #[arg(long, conflicts_with = "tar")]
+    pub gzip_output: bool,
+
+    /// Glob, which names of files found in input directories have to match. Gzipped files match if their names do
+    /// without the ".gz" extension, so the default finds "game.html.gz" too. Input directories are searched
+    /// recursively and outputs of the found logs are put in the same subdirectories under the output directory.
+    #[arg(long, value_name = "GLOB", value_parser = Pattern::new, default_value = "*.html")]
+    pub file_glob: Pattern,
+
+    /// Read paths from standard input, separated by whitespaces. They are expanded as glob patterns too.
+    #[arg(long)]
+    pub stdin: bool,
+
+    /// Keep running after filtering the logs and filter each of them again whenever it changes, until interrupted with
+    /// Ctrl-C. Outputs are overwritten.
+    #[arg(long, conflicts_with_all = ["stdin", "tar", "merge"])]
+    pub watch: bool,
+
+    /// Follow each log as it grows, like `tail -f`, appending newly kept messages to its output, until the log is
+    /// closed by its footer or the program is interrupted with Ctrl-C. Logs are checked for new contents every half a
+    /// second, and only the part appended since the last check is read. Outputs are closed by the usual footer after
+    /// every append. Options, which need the whole log, like --toc or --dedup, can't be used, and nothing is written
+    /// for a log until its chat pane begins.
+    #[arg(long, conflicts_with_all = [
+        "watch", "merge", "tar", "counting", "stdout", "gzip_output", "split_size", "encoding_detect"
+    ])]
+    pub follow: bool,
+
+    /// Exits the program if failed to filter one or more paths
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Allow overwrite of the output file
+    #[arg(long)]
+    pub overwrite: bool,
+
+    /// Allow overwrite only of the output files matching this glob, e.g. "out/filtered_*.html". Other existing outputs
+    /// fail as without --overwrite.
+    #[arg(long, value_name = "GLOB", value_parser = Pattern::new)]
+    pub overwrite_paths: Option<Pattern>,
+
+    /// Match case of include & exclude patterns. Classes and senders have their own --class-case and --speaker-case.
+    #[arg(long)]
+    pub match_case: bool,
+
+    /// Treat include & exclude patterns as regexes
+    #[arg(long)]
+    pub regex: bool,
+
+    /// Reject regexes with potentially expensive constructs: unbounded repetitions ("*", "+", "{n,}"), repetition
+    /// bounds over 100 and nested repetitions
+    #[arg(long)]
+    pub safe_regex: bool,
+
+    /// Patterns that has to be included in the output. Can be repeated, messages matching any of them are included.
+    /// Patterns prefixed with a class, like "class:say/nuke", only match messages with that class.
+    #[arg(short, long)]
+    pub include: Vec<String>,
+
+    /// How include patterns are combined: "any" keeps messages matching any of them, "all" only the ones matching all
+    /// of them. Defaults to "any".
+    #[arg(long, value_enum, value_name = "MODE")]
+    pub include_mode: Option<IncludeMode>,
+
+    /// Keep only messages, which matched include patterns weigh at least this much in total, instead of combining them
+    /// by --include-mode. Weights are given after a colon, like "breach:3", and default to 1.
+    #[arg(long, value_name = "SCORE")]
+    pub min_score: Option<u32>,
+
+    /// What include & exclude patterns are matched against: "full" is the whole markup of a message, "text" only its
+    /// visible text, so that e.g. "span" doesn't match `<span>` tags. Kept messages are written with their markup
+    /// either way. Defaults to "full".
+    #[arg(long, value_enum, value_name = "FIELD")]
+    pub match_field: Option<MatchField>,
+
+    /// Files with more include patterns, one per line. Blank lines and lines starting with "#" are ignored. Can be
+    /// repeated.
+    #[arg(long, value_name = "FILE")]
+    pub include_file: Vec<PathBuf>,
+
+    /// Use the current clipboard text as an include pattern
+    #[cfg(feature = "clipboard")]
+    #[arg(long)]
+    pub include_from_clipboard: bool,
+
+    /// Patterns that has to be excluded from the output. Can be repeated, messages matching any of them are excluded.
+    /// Patterns prefixed with a class, like "class:ooc/test", only exclude messages with that class.
+    #[arg(short, long)]
+    pub exclude: Vec<String>,
+
+    /// Files with more exclude patterns, one per line. Blank lines and lines starting with "#" are ignored. Can be
+    /// repeated.
+    #[arg(long, value_name = "FILE")]
+    pub exclude_file: Vec<PathBuf>,
+
+    /// Keep only messages, which opening tags have any of these classes, e.g. "say,radio". Can be used without include
+    /// & exclude patterns.
+    #[arg(long, value_name = "CLASSES", value_delimiter = ',')]
+    pub class: Option<Vec<String>>,
+
+    /// Match classes given by --class case-sensitively. Case is ignored by default, regardless of --match-case.
+    #[arg(long)]
+    pub class_case: bool,
+
+    /// Keep only messages sent by the player with this name. Messages without a sender, like system and radio ones,
+    /// are dropped. Case is ignored unless --speaker-case is set.
+    #[arg(long, value_name = "NAME")]
+    pub sender: Option<String>,
+
+    /// Match the name given by --sender case-sensitively. Case is ignored by default, regardless of --match-case.
+    #[arg(long)]
+    pub speaker_case: bool,
+
+    /// Keep only messages sent at this time or later, e.g. "12:05" or "12:05:30". Time is read from the timestamp span
+    /// of each message, `<span class="timestamp">[12:05:30]</span>`. Messages without one are kept, unless
+    /// --require-timestamp is set.
+    #[arg(long, value_name = "TIME")]
+    pub after: Option<Timestamp>,
+
+    /// Keep only messages sent at this time or earlier, in the same format as --after
+    #[arg(long, value_name = "TIME")]
+    pub before: Option<Timestamp>,
+
+    /// Drop messages without a timestamp when filtering by --after or --before
+    #[arg(long)]
+    pub require_timestamp: bool,
+
+    /// Keep the messages which would be dropped and drop the ones which would be kept. Applies to the final decision,
+    /// not to each pattern, and doesn't bring back messages matching the deny config.
+    #[arg(short = 'v', long)]
+    pub invert: bool,
+
+    /// Regex, which the whole input document has to match. Documents which don't match it are skipped entirely,
+    /// without filtering individual messages.
+    #[arg(long, value_name = "PATTERN")]
+    pub require_doc_regex: Option<String>,
+
+    /// Path to a config file in TOML, JSON or YAML, picked by its extension. Pattern arguments passed along with it
+    /// override its settings, e.g. --include replaces its includes and --regex turns regexes on.
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Path to a denylist config file. Messages matching it are dropped regardless of other patterns.
+    #[arg(long, value_name = "FILE")]
+    pub deny_config: Option<PathBuf>,
+
+    /// Print the effective configuration, along with the deny config, and exit. It is printed as JSON with --format
+    /// json or jsonl and as TOML otherwise.
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Write a config file with every setting at its default value, explained by comments, and exit. An existing file
+    /// is overwritten only with --overwrite.
+    #[arg(long, value_name = "FILE")]
+    pub init_config: Option<PathBuf>,
+
+    /// Load and validate the config (or patterns from the arguments) and exit, without processing any logs
+    #[arg(long)]
+    pub check_config: bool,
+
+    /// Path to a tar archive, which will contain all filtered logs as entries instead of separate files. Entry names
+    /// are the same as output paths, without leading "./". Archive is gzipped if its name ends with ".gz" or ".tgz".
+    /// Missing directories in its path are created.
+    #[arg(long, value_name = "ARCHIVE")]
+    pub tar: Option<PathBuf>,
+
+    /// Write each filtered log in parts of at most this many bytes, named like "filtered_game.part001.html". Each part
+    /// has the header and the footer of the log, and parts are split only between messages, so a single long message
+    /// may still exceed the size. Ignored for other formats than HTML.
+    #[arg(long, value_name = "BYTES", conflicts_with_all = ["stdout", "counting"])]
+    pub split_size: Option<usize>,
+
+    /// Stop filtering each log after this many kept messages, skipping the rest of it. Streamed logs aren't read any
+    /// further, so their totals count only the part read. Messages collapsed by --dedup don't count, as the limit is
+    /// applied after deduplication.
+    #[arg(long, value_name = "K")]
+    pub limit: Option<usize>,
+
+    /// Stop after this many kept messages across all files. Remaining files are skipped.
+    #[arg(long, value_name = "N")]
+    pub limit_total: Option<usize>,
+
+    /// What to do when several inputs resolve to the same output path
+    #[arg(long, value_enum, default_value_t = CollisionPolicy::Error)]
+    pub on_collision: CollisionPolicy,
+
+    /// Maximum number of logs processed in parallel. Defaults to the number of CPUs. Logs are processed one by one
+    /// with --tar, --stdout or --limit-total, since their outputs depend on the order of inputs.
+    #[arg(short, long, value_name = "N", value_parser = clap::value_parser!(u16).range(1..))]
+    pub jobs: Option<u16>,
+
+    /// Don't show the progress bar. It is hidden anyway if the standard output or error isn't a terminal.
+    #[arg(short, long)]
+    pub quiet: bool,
+
+    /// Print time spent reading, splitting, matching and writing for each file and in total
+    #[arg(long)]
+    pub profile: bool,
+
+    /// Match include patterns against the visible text of a sliding window of consecutive messages joined with spaces,
+    /// keeping the whole window when it matches. Exclude patterns are still checked per message.
+    #[arg(long)]
+    pub window_match: bool,
+
+    /// Number of consecutive messages in the window for --window-match
+    #[arg(long, value_name = "K", default_value_t = 2, value_parser = clap::value_parser!(u16).range(1..))]
+    pub window_size: u16,
+
+    /// Keep this many messages before and after every matching message too. Overlapping contexts are merged, so no
+    /// message is kept twice.
+    #[arg(long, value_name = "N")]
+    pub context: Option<usize>,
+
+    /// Keep only the last N matching messages of each sender, e.g. to see what everyone said last about the matched
+    /// subject. Messages without a sender, like radio and system ones, count as sent by one more sender. --context is
+    /// added around the messages left.
+    #[arg(long, value_name = "N")]
+    pub last_per_speaker: Option<usize>,
+
+    /// Keep only the matching messages sent in this third of the round, between the first and the last timestamp of
+    /// each log. Messages without a timestamp belong to the phase of the last timestamped one before them.
+    #[arg(long, value_enum)]
+    pub phase: Option<Phase>,
+
+    /// Maximum size of each output in bytes. Once the next kept message would exceed it, the rest of the matches are
+    /// omitted and the document is closed.
+    #[arg(long, value_name = "BYTES")]
+    pub output_budget: Option<usize>,
+
+    /// Retry reading an input this many times with increasing delays if it is temporarily unavailable, e.g. locked by
+    /// the program writing it. Missing and invalid inputs fail right away.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub retry_read: u32,
+
+    /// HTML inserted between kept messages which weren't adjacent in the original log, e.g. '<hr class="gap">'
+    #[arg(long, value_name = "HTML")]
+    pub separator: Option<String>,
+
+    /// File with an HTML snippet wrapping each kept message of the HTML output, e.g.
+    /// '<article title="{time} {speaker}">{raw}</article>'. Placeholders {raw}, {text}, {speaker}, {time} and {class}
+    /// are replaced by the markup, visible text, sender, timestamp and classes of the message. Messages are written as
+    /// they are without it.
+    #[arg(long, value_name = "FILE")]
+    pub message_template: Option<PathBuf>,
+
+    /// Print the visible text of each kept message to the standard output instead of writing any outputs, with the
+    /// parts matched by include patterns highlighted if the output is a terminal. Filter options, like --context, are
+    /// ignored.
+    #[arg(long, conflicts_with_all = ["outputs", "out_dir", "stdout", "counting", "merge", "tar"])]
+    pub preview: bool,
+
+    /// Check the structure of each log without filtering it, print the problems found and exit: missing chat panes,
+    /// messages with unbalanced div tags and logs cut off before their footer. Exits with an error if any log has
+    /// problems in strict mode.
+    #[arg(long)]
+    pub validate: bool,
+
+    /// Check the config, inputs and outputs without processing anything, print a report and exit. Unreadable inputs
+    /// and existing outputs are only blocking in strict mode.
+    #[arg(long)]
+    pub preflight: bool,
+
+    /// Detect the encoding of each input instead of assuming UTF-8
+    #[arg(long)]
+    pub encoding_detect: bool,
+
+    /// Remove <script> blocks from the header and kept messages, so the output doesn't run scripts when opened
+    #[arg(long)]
+    pub strip_scripts: bool,
+
+    /// Remove the timestamp of each kept message, e.g. to share a log without revealing when things were said.
+    /// Timestamps are still matched by --after and --before, and tables of contents are labeled without them.
+    #[arg(long)]
+    pub strip_timestamps: bool,
+
+    /// Replace the sender of each kept message with a pseudonym like "Player1", the same one for a sender in all logs.
+    /// Only the sender's name element is replaced, names mentioned in the text of messages are kept.
+    #[arg(long)]
+    pub anonymize_speakers: bool,
+
+    /// Pick pseudonyms from this seed and the senders' names instead of numbering them in the order of appearance, so
+    /// that the same seed gives a sender the same pseudonym in every run
+    #[arg(long, value_name = "SEED", requires = "anonymize_speakers")]
+    pub anon_seed: Option<u64>,
+
+    /// Write each anonymized sender along with the pseudonym into this file, as JSON if its extension is ".json" and as
+    /// CSV otherwise
+    #[arg(long, value_name = "FILE", requires = "anonymize_speakers", conflicts_with_all = ["watch", "follow"])]
+    pub anon_map: Option<PathBuf>,
+
+    /// Skip messages longer than this many bytes without matching them. In strict mode such a message fails the
+    /// whole log instead.
+    #[arg(long, value_name = "BYTES")]
+    pub max_message_length: Option<usize>,
+
+    /// Octal Unix permissions to set on output files after writing them, e.g. 444. Ignored on other platforms.
+    #[arg(long, value_name = "MODE", value_parser = parse_mode)]
+    pub chmod: Option<u32>,
+
+    /// Format of the filtered logs. Table of contents and separators are only added to HTML. Can be repeated or
+    /// comma-separated to filter each log into several formats at once, e.g. "html,json", in which case each output is
+    /// named by the extension of its format, like "filtered_game.html" and "filtered_game.json".
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "html")]
+    pub format: Vec<OutputFormat>,
+
+    /// Structure of the logs: "tg" for logs saved by tgchat, "paradise" for the ones saved by goonchat, or "generic"
+    /// for plain logs with a message on each line. Logs of other parsers than tg aren't streamed, and can't be merged,
+    /// followed, previewed, validated or split into parts.
+    #[arg(long, value_enum, default_value_t = BuiltinParser::Tg)]
+    pub parser: BuiltinParser,
+
+    /// Collapse runs of identical consecutive kept messages into the first of them. Timestamps are ignored when
+    /// comparing messages. Messages are deduplicated once matched, along with their context, and before
+    /// --output-budget and --limit are applied. With --merge, messages identical to ones of earlier merged logs are
+    /// removed as well, keeping the first occurrence.
+    #[arg(long)]
+    pub dedup: bool,
+
+    /// Append " (x N)" to the text of messages collapsed by --dedup, where N is the length of the run
+    #[arg(long, requires = "dedup")]
+    pub dedup_count: bool,
+
+    /// Pretty-print the JSON output. --output-budget is still checked against the compact JSON.
+    #[arg(long)]
+    pub pretty: bool,
+
+    /// Prepend a table of contents linking to every kept message. Links are labeled with timestamps and senders of the
+    /// messages, or with their positions in the original log if they have neither.
+    #[arg(long)]
+    pub toc: bool,
+}
+
+impl Cli {
+    /// Whether the output at `output_path` may be overwritten, either by --overwrite, --overwrite-paths or --watch
+    pub fn may_overwrite(&self, output_path: &Path) -> bool {
+        self.overwrite
+            || self.watch
+            || self.overwrite_paths.as_ref().is_some_and(|pattern| {
+                pattern.matches_path(output_path.strip_prefix(".").unwrap_or(output_path))
+            })
+    }
+}
+
+impl TryFrom<&Cli> for Config {
+    type Error = anyhow::Error;
+
+    /// Builds the config exactly as the CLI uses it: loaded from the config file if one is given, with the pattern
+    /// arguments passed explicitly overriding its settings, or from the pattern arguments alone otherwise, with the
+    /// deny config attached.
+    fn try_from(cli: &Cli) -> Result<Self, Self::Error> {
+        #[allow(unused_mut)]
+        let mut include = cli.include.clone();
+        #[cfg(feature = "clipboard")]
+        if cli.include_from_clipboard {
+            include.push(read_clipboard().map_err(|err| {
+                anyhow::format_err!(
+                    "Failed to read the include pattern from the clipboard: {}",
+                    err
+                )
+            })?);
+        }
+
+        let args = ConfigArgs {
+            regex: cli.regex,
+            include,
+            include_files: cli.include_file.clone(),
+            exclude: cli.exclude.clone(),
+            exclude_files: cli.exclude_file.clone(),
+            match_case: cli.match_case,
+            safe_regex: cli.safe_regex,
+            invert: cli.invert,
+            include_mode: cli.include_mode,
+            min_score: cli.min_score,
+            match_field: cli.match_field,
+            classes: cli.class.clone(),
+            class_case: cli.class_case,
+            sender: cli.sender.clone(),
+            speaker_case: cli.speaker_case,
+            after: cli.after,
+            before: cli.before,
+            require_timestamp: cli.require_timestamp,
+            require_doc_regex: cli.require_doc_regex.clone(),
+        };
+        let config = match &cli.config {
+            Some(config_path) => Config::load_with_overrides(config_path, args).map_err(|err| {
+                anyhow::format_err!(
+                    "Failed to load config from {}: {}",
+                    config_path.to_string_lossy(),
+                    err
+                )
+            })?,
+            None => Config::from_args(args)
+                .map_err(|err| anyhow::format_err!("Failed to parse arguments: {}", err))?,
+        };
+
+        match &cli.deny_config {
+            Some(deny_config_path) => {
+                let deny_config = Config::load(deny_config_path).map_err(|err| {
+                    anyhow::format_err!(
+                        "Failed to load deny config from {}: {}",
+                        deny_config_path.to_string_lossy(),
+                        err
+                    )
+                })?;
+                Ok(config.with_deny_config(deny_config))
+            }
+            None => Ok(config),
+        }
+    }
+}
+
+/// What to do when several inputs resolve to the same output path
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CollisionPolicy {
+    /// Exit with an error before processing anything
+    Error,
+    /// Append an index to the names of colliding outputs, e.g. "filtered_game_1.html"
+    Index,
+}
+
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    u32::from_str_radix(mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("{} is not an octal mode between 0 and 7777", mode))
+}
+
+#[cfg(feature = "clipboard")]
+fn read_clipboard() -> Result<String, anyhow::Error> {
+    let mut clipboard = arboard::Clipboard::new()
+        .map_err(|err| anyhow::format_err!("clipboard is not available: {}", err))?;
+    let text = clipboard
+        .get_text()
+        .map_err(|err| anyhow::format_err!("failed to get the clipboard text: {}", err))?;
+    if text.is_empty() {
+        Err(anyhow::Error::msg("clipboard is empty"))?
+    }
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+
+    use serde_json::{Value, json};
+
+    use super::*;
+    use crate::test_files::TestFiles;
+
+    fn config(args: &[&str]) -> anyhow::Result<Config> {
+        let cli = Cli::try_parse_from(iter::once("ss13_mlogfilter").chain(args.iter().copied()))?;
+        Config::try_from(&cli)
+    }
+
+    fn config_error(args: &[&str]) -> String {
+        config(args).unwrap_err().to_string()
+    }
+
+    fn serialized(args: &[&str]) -> Value {
+        serde_json::to_value(config(args).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn substring_patterns_are_lowercased() {
+        let config = serialized(&["-i", "Admin", "-i", "Nuke", "-e", "OOC"]);
+        assert_eq!(config["include"], json!(["admin", "nuke"]));
+        assert_eq!(config["exclude"], json!(["ooc"]));
+        assert_eq!(config["regex"], json!(false));
+        assert_eq!(config["match_case"], json!(false));
+        assert_eq!(config["include_mode"], json!("any"));
+    }
+
+    #[test]
+    fn regexes_and_matched_case_are_kept_as_is() {
+        let config = serialized(&["--regex", "-i", "[A-Z]dmin", "--match-case"]);
+        assert_eq!(config["include"], json!(["[A-Z]dmin"]));
+        assert_eq!(config["regex"], json!(true));
+        assert_eq!(config["match_case"], json!(true));
+
+        let err = config_error(&["--regex", "-i", "[A-Z"]);
+        assert!(err.starts_with("Failed to parse arguments"), "{}", err);
+    }
+
+    #[test]
+    fn markup_filters_and_modes() {
+        let config = serialized(&[
+            "--class",
+            "say,radio",
+            "--sender",
+            "John Doe",
+            "--after",
+            "12:05",
+            "--include-mode",
+            "all",
+            "--match-field",
+            "text",
+            "-v",
+        ]);
+        assert_eq!(config["classes"], json!(["say", "radio"]));
+        assert_eq!(config["sender"], json!("John Doe"));
+        assert_eq!(config["after"], json!("12:05:00"));
+        assert_eq!(config["before"], Value::Null);
+        assert_eq!(config["include_mode"], json!("all"));
+        assert_eq!(config["match_field"], json!("text"));
+        assert_eq!(config["invert"], json!(true));
+    }
+
+    #[test]
+    fn class_alone_is_a_valid_filter() {
+        let config = config(&["--class", "say"]).unwrap();
+        config.validate().unwrap();
+        assert!(
+            config
+                .matches("<div class=\"ChatMessage say\"><b>John</b> says, \"hi\"</div>")
+                .unwrap()
+        );
+        assert!(
+            !config
+                .matches("<div class=\"ChatMessage radio\">[Common] John says, \"hi\"</div>")
+                .unwrap()
+        );
+        assert!(!config.matches("<div>say</div>").unwrap());
+    }
+
+    #[test]
+    fn class_and_speaker_case_are_independent_of_match_case() {
+        let message = "<div class=\"ChatMessage say\"><b>John</b> says, \"Hi\"</div>";
+        let matches = |args: &[&str]| config(args).unwrap().matches(message).unwrap();
+        assert!(matches(&[
+            "--class",
+            "SAY",
+            "--sender",
+            "john",
+            "--match-case"
+        ]));
+        assert!(!matches(&["--class", "SAY", "--class-case"]));
+        assert!(matches(&["--class", "say", "--class-case"]));
+        assert!(!matches(&["--sender", "john", "--speaker-case"]));
+        assert!(matches(&["--sender", "John", "--speaker-case"]));
+    }
+
+    #[test]
+    fn sender_alone_is_a_valid_filter() {
+        let config = config(&["--sender", "John"]).unwrap();
+        config.validate().unwrap();
+        assert!(
+            config
+                .matches("<div class=\"ChatMessage\"><b>john</b> says, \"hi\"</div>")
+                .unwrap()
+        );
+        assert!(
+            !config
+                .matches("<div class=\"ChatMessage\"><b>Jane</b> says, \"John?\"</div>")
+                .unwrap()
+        );
+        assert!(
+            !config
+                .matches("<div class=\"ChatMessage\">John has joined the round.</div>")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn arguments_override_the_config_file() {
+        let files = TestFiles::new();
+        let path = files.write(
+            "override.toml",
+            "regex = false\nmatch_case = false\ninclude = [\"nuke\"]\nexclude = [\"OOC\"]\n",
+        );
+        let path = path.to_str().unwrap();
+
+        let loaded = serialized(&["-c", path]);
+        assert_eq!(loaded["include"], json!(["nuke"]));
+        assert_eq!(loaded["exclude"], json!(["ooc"]));
+
+        let overridden = serialized(&["-c", path, "-i", "Syndicate", "--invert"]);
+        assert_eq!(overridden["include"], json!(["syndicate"]));
+        assert_eq!(overridden["exclude"], json!(["ooc"]));
+        assert_eq!(overridden["invert"], json!(true));
+
+        let err = config_error(&["-c", "missing.toml"]);
+        assert!(
+            err.starts_with("Failed to load config from missing.toml"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn deny_config_drops_matching_messages() {
+        let files = TestFiles::new();
+        let path = files.write(
+            "deny.toml",
+            "regex = false\nmatch_case = false\ninclude = \"secret\"\n",
+        );
+        let config = config(&["-i", "nuke", "--deny-config", path.to_str().unwrap()]).unwrap();
+        assert!(config.matches("the nuke is armed").unwrap());
+        assert!(!config.matches("the secret nuke is armed").unwrap());
+    }
+}
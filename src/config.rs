@@ -1,107 +1,1055 @@
-use std::{fs::read_to_string, path::Path};
+use std::{
+    borrow::Cow,
+    fs::read_to_string,
+    path::{Path, PathBuf},
+};
 
-use regex::Regex;
-use serde::{Deserialize, Serialize};
+use clap::ValueEnum;
+use regex::{Regex, RegexBuilder};
+use regex_syntax::hir::{Hir, HirKind};
+use serde::{Deserialize, Deserializer, Serialize};
 
+use crate::{
+    parsing::{message_classes, message_sender, plain_text},
+    timestamp::{Timestamp, message_timestamp},
+};
+
+/// Largest repetition bound allowed in safe regex mode
+const SAFE_REGEX_MAX_REPETITION: u32 = 100;
+
+/// Prefix of patterns scoped to a class, as in "class:ooc/test"
+const CLASS_SCOPE_PREFIX: &str = "class:";
+
+/// Config with every supported setting at its default value, explained by comments. Written by `--init-config`.
+pub const DEFAULT_CONFIG: &str = r#"# Config of ss13_mlogfilter. Messages are kept if they match any include pattern and no
+# exclude pattern. At least one include or exclude pattern, class, sender or time bound has to be set.
+
+# Treat include & exclude patterns as regexes instead of plain substrings
+regex = false
+# Match patterns case-sensitively
+match_case = false
+# Reject regexes with potentially expensive constructs, like unbounded repetitions
+safe_regex = false
+
+# Messages are kept if they match any of these, e.g. ["Syndicate", "nuke"]. Patterns prefixed with a class, like
+# "class:ooc/test", only match messages of that class, in both include and exclude.
+include = []
+# How include patterns are combined: "any" keeps messages matching any of them, "all" only the ones matching all
+include_mode = "any"
+# Keep only messages, which matched include patterns weigh this much in total, instead of combining them by
+# include_mode. Weights are given after a colon, like "breach:3", and default to 1.
+# min_score = 3
+# Messages are dropped if they match any of these, e.g. ["class:ooc/test"] drops OOC messages with "test" only
+exclude = []
+# What patterns are matched against: "full" is the whole markup of a message, "text" only its visible text, so that
+# patterns don't match tags and attributes
+match_field = "full"
+
+# Keep the messages which would be dropped and drop the ones which would be kept
+invert = false
+# Drop messages without a timestamp when filtering by "after" or "before"
+require_timestamp = false
+
+# Keep only messages, which opening tags have any of these classes
+# classes = ["say", "radio"]
+# Match classes case-sensitively, regardless of match_case
+class_case = false
+
+# Keep only messages sent by the player with this name
+# sender = "John Doe"
+# Match the sender's name case-sensitively, regardless of match_case
+speaker_case = false
+
+# Keep only messages sent within this time range, as HH:MM or HH:MM:SS
+# after = "12:00"
+# before = "12:30:00"
+
+# Regex, which the whole document has to match. Documents not matching it are skipped.
+# require_doc_regex = "Nuclear Emergency"
+"#;
+
+/// Settings given as arguments. A config is either built from them alone, or loaded with them overriding the loaded
+/// settings, in which case flags can only be turned on, and empty lists and `None` keep the loaded values.
+#[derive(Debug, Default)]
+pub struct ConfigArgs {
+    pub regex: bool,
+    pub include: Vec<String>,
+    /// Files with more include patterns, one per line
+    pub include_files: Vec<PathBuf>,
+    pub exclude: Vec<String>,
+    /// Files with more exclude patterns, one per line
+    pub exclude_files: Vec<PathBuf>,
+    pub match_case: bool,
+    pub safe_regex: bool,
+    pub invert: bool,
+    pub include_mode: Option<IncludeMode>,
+    pub min_score: Option<u32>,
+    pub match_field: Option<MatchField>,
+    pub classes: Option<Vec<String>>,
+    pub class_case: bool,
+    pub sender: Option<String>,
+    pub speaker_case: bool,
+    pub after: Option<Timestamp>,
+    pub before: Option<Timestamp>,
+    pub require_timestamp: bool,
+    pub require_doc_regex: Option<String>,
+}
+
+/// How the include patterns are combined
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum IncludeMode {
+    /// Messages matching any of the patterns are kept
+    #[default]
+    Any,
+    /// Only messages matching all of the patterns are kept
+    All,
+}
+
+/// Part of a message, which include & exclude patterns are matched against
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchField {
+    /// Whole markup of the message, including its tags and attributes
+    #[default]
+    Full,
+    /// Visible text of the message
+    Text,
+}
+
+/// Formats of config files
+#[derive(Debug, Clone, Copy)]
+enum ConfigFormat {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Formats in the order they are tried when the extension of the config file is unknown
+    const ALL: [Self; 3] = [Self::Toml, Self::Json, Self::Yaml];
+
+    /// Picks the format by the extension of the config file, ignoring its case
+    fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_string_lossy().to_ascii_lowercase();
+        match extension.as_str() {
+            "toml" => Some(Self::Toml),
+            "json" => Some(Self::Json),
+            "yaml" | "yml" => Some(Self::Yaml),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Toml => "TOML",
+            Self::Json => "JSON",
+            Self::Yaml => "YAML",
+        }
+    }
+
+    fn parse<T: for<'de> Deserialize<'de>>(self, contents: &str) -> anyhow::Result<T> {
+        Ok(match self {
+            Self::Toml => toml::from_str(contents)?,
+            Self::Json => serde_json::from_str(contents)?,
+            Self::Yaml => serde_yaml_ng::from_str(contents)?,
+        })
+    }
+}
+
+/// Patterns and settings, which messages are matched against. Loaded from a TOML, JSON or YAML file or built from
+/// arguments.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
     regex: bool,
-    include: Option<String>,
-    exclude: Option<String>,
+    /// Messages are kept if they match any of these. A single string is accepted too. Patterns prefixed with
+    /// "class:NAME/" only match messages with the class NAME, here and in `exclude`.
+    #[serde(default, deserialize_with = "one_or_many")]
+    include: Vec<String>,
+    /// How the include patterns are combined
+    #[serde(default)]
+    include_mode: IncludeMode,
+    /// Total weight of the matched include patterns, which messages need to be kept, replacing `include_mode`. Weights
+    /// are given by a ":WEIGHT" suffix of the include patterns, e.g. "breach:3", and default to 1.
+    #[serde(default)]
+    min_score: Option<u32>,
+    /// Messages are dropped if they match any of these. A single string is accepted too.
+    #[serde(default, deserialize_with = "one_or_many")]
+    exclude: Vec<String>,
+    /// What the include & exclude patterns are matched against. The output keeps the whole markup either way.
+    #[serde(default)]
+    match_field: MatchField,
     match_case: bool,
+    #[serde(default)]
+    safe_regex: bool,
+    /// Flip the final decision of `matches`, so matching messages are dropped and the rest are kept
+    #[serde(default)]
+    invert: bool,
+    /// Messages are kept only if their opening tag has any of these classes, e.g. "say" or "radio". Case is ignored
+    /// unless `class_case` is set.
+    #[serde(default)]
+    classes: Option<Vec<String>>,
+    /// Match classes case-sensitively. Independent of `match_case`, which is about the contents of messages.
+    #[serde(default)]
+    class_case: bool,
+    /// Messages are kept only if they were sent by the player with this name. Case is ignored unless `speaker_case` is
+    /// set.
+    #[serde(default)]
+    sender: Option<String>,
+    /// Match the sender's name case-sensitively. Independent of `match_case`, which is about the contents of messages.
+    #[serde(default)]
+    speaker_case: bool,
+    /// Messages are kept only if they were sent at this time or later
+    #[serde(default)]
+    after: Option<Timestamp>,
+    /// Messages are kept only if they were sent at this time or earlier
+    #[serde(default)]
+    before: Option<Timestamp>,
+    /// Drop messages without a timestamp when `after` or `before` is set, instead of keeping them
+    #[serde(default)]
+    require_timestamp: bool,
+    /// Regex, which the whole document has to match to be filtered at all. Always treated as a regex.
+    #[serde(default)]
+    require_doc_regex: Option<String>,
 
-    // compiled regexes
+    // patterns split from their class scopes, and the regexes compiled from them
+    #[serde(skip)]
+    include_patterns: Vec<ScopedPattern>,
+    #[serde(skip)]
+    exclude_patterns: Vec<ScopedPattern>,
     #[serde(skip)]
-    include_regex: Option<Regex>,
+    include_regexes: Vec<Regex>,
     #[serde(skip)]
-    exclude_regex: Option<Regex>,
+    exclude_regexes: Vec<Regex>,
+    #[serde(skip)]
+    document_regex: Option<Regex>,
+
+    /// Config, which messages are matched against first. Messages it matches are dropped regardless of this config.
+    /// Serialized to show the effective config, but never loaded from a file, since it comes from its own one.
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    deny_config: Option<Box<Config>>,
 }
 
 impl Config {
-    pub fn from_args(
-        regex: bool,
-        include: Option<String>,
-        exclude: Option<String>,
-        match_case: bool,
-    ) -> anyhow::Result<Self> {
+    /// Builds the config from the arguments alone
+    pub fn from_args(args: ConfigArgs) -> anyhow::Result<Self> {
         let mut config = Self {
-            regex,
-            include,
-            exclude,
-            match_case,
-            include_regex: None,
-            exclude_regex: None,
+            regex: args.regex,
+            include: with_pattern_files(args.include, &args.include_files, "include", args.regex)?,
+            exclude: with_pattern_files(args.exclude, &args.exclude_files, "exclude", args.regex)?,
+            match_case: args.match_case,
+            safe_regex: args.safe_regex,
+            invert: args.invert,
+            include_mode: args.include_mode.unwrap_or_default(),
+            min_score: args.min_score,
+            match_field: args.match_field.unwrap_or_default(),
+            classes: args.classes,
+            class_case: args.class_case,
+            sender: args.sender,
+            speaker_case: args.speaker_case,
+            after: args.after,
+            before: args.before,
+            require_timestamp: args.require_timestamp,
+            require_doc_regex: args.require_doc_regex,
+            include_patterns: Vec::new(),
+            exclude_patterns: Vec::new(),
+            include_regexes: Vec::new(),
+            exclude_regexes: Vec::new(),
+            document_regex: None,
+            deny_config: None,
         };
-        if !match_case {
-            config.include = config.include.map(|pattern| pattern.to_lowercase());
+        config.prepare()?;
+        Ok(config)
+    }
 
-            config.exclude = config.exclude.map(|pattern| pattern.to_lowercase());
+    /// Splits the patterns from their class scopes, normalizes them and compiles the regexes. Has to be called on each
+    /// constructed config, regardless of where its fields came from.
+    fn prepare(&mut self) -> anyhow::Result<()> {
+        // regexes handle case insensitivity themselves, lowercasing would break patterns like [A-Z]
+        if !self.match_case && !self.regex {
+            self.include = self
+                .include
+                .iter()
+                .map(|pattern| lowercase_pattern(pattern))
+                .collect();
+
+            self.exclude = self
+                .exclude
+                .iter()
+                .map(|pattern| lowercase_pattern(pattern))
+                .collect();
         }
-        if regex {
-            config.compile_regexes()?;
+        self.include_patterns = ScopedPattern::parse_all(&self.include, self.min_score.is_some());
+        self.exclude_patterns = ScopedPattern::parse_all(&self.exclude, false);
+        if self.regex {
+            self.compile_regexes()?;
         }
-        Ok(config)
+        self.compile_document_regex()
     }
 
     fn compile_regexes(&mut self) -> anyhow::Result<()> {
-        if let Some(include) = &self.include {
-            self.include_regex = Some(Regex::new(include).map_err(|err| {
-                anyhow::format_err!("failed to compile include regex from {}: {}", include, err)
-            })?);
-        };
+        let include = ScopedPattern::patterns(&self.include_patterns);
+        let exclude = ScopedPattern::patterns(&self.exclude_patterns);
+        if self.safe_regex {
+            for pattern in include.iter().chain(exclude.iter()) {
+                validate_safe_regex(pattern)?;
+            }
+        }
 
-        if let Some(exclude) = &self.exclude {
-            self.exclude_regex = Some(Regex::new(exclude).map_err(|err| {
-                anyhow::format_err!("failed to compile exclude regex from {}: {}", exclude, err)
-            })?);
-        };
+        self.include_regexes = compile_patterns(&include, "include", self.match_case)?;
+        self.exclude_regexes = compile_patterns(&exclude, "exclude", self.match_case)?;
         Ok(())
     }
 
+    fn compile_document_regex(&mut self) -> anyhow::Result<()> {
+        if let Some(pattern) = &self.require_doc_regex {
+            if self.safe_regex {
+                validate_safe_regex(pattern)?;
+            }
+            self.document_regex = Some(
+                RegexBuilder::new(pattern)
+                    .case_insensitive(!self.match_case)
+                    .build()
+                    .map_err(|err| {
+                        anyhow::format_err!(
+                            "failed to compile document regex from {}: {}",
+                            pattern,
+                            err
+                        )
+                    })?,
+            );
+        }
+        Ok(())
+    }
+
+    /// Loads the config from the file at `path`. Its format is picked by the extension: ".toml", ".json", ".yaml" or
+    /// ".yml". Files with other extensions are parsed as each format in turn, until one of them succeeds.
     pub fn load<T: AsRef<Path>>(path: T) -> anyhow::Result<Self> {
-        let toml_string = read_to_string(path).map_err(anyhow::Error::from)?;
-        let mut config: Self = toml::from_str(&toml_string).map_err(anyhow::Error::from)?;
+        Self::load_with_overrides(path, ConfigArgs::default())
+    }
 
-        if config.regex {
-            config.compile_regexes()?;
+    /// Loads the config, replacing its settings with the ones set in `overrides`
+    pub fn load_with_overrides<T: AsRef<Path>>(
+        path: T,
+        overrides: ConfigArgs,
+    ) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let contents = read_to_string(path).map_err(anyhow::Error::from)?;
+        let mut config: Self = match ConfigFormat::from_path(path) {
+            Some(format) => format.parse(&contents).map_err(|err| {
+                anyhow::format_err!("failed to parse the config as {}: {}", format.name(), err)
+            })?,
+            None => {
+                let mut errors = Vec::new();
+                let config = ConfigFormat::ALL.iter().find_map(|format| {
+                    format
+                        .parse(&contents)
+                        .map_err(|err| errors.push(format!("as {}: {}", format.name(), err)))
+                        .ok()
+                });
+                config.ok_or_else(|| {
+                    anyhow::format_err!(
+                        "failed to parse the config in any format, tried {}",
+                        errors.join("; ")
+                    )
+                })?
+            }
+        };
+
+        // overrides go before preparing, so that patterns are normalized and compiled with the final settings
+        config.regex |= overrides.regex;
+        config.match_case |= overrides.match_case;
+        config.safe_regex |= overrides.safe_regex;
+        config.invert |= overrides.invert;
+        config.require_timestamp |= overrides.require_timestamp;
+        config.class_case |= overrides.class_case;
+        config.speaker_case |= overrides.speaker_case;
+        let include = with_pattern_files(
+            overrides.include,
+            &overrides.include_files,
+            "include",
+            config.regex,
+        )?;
+        if !include.is_empty() {
+            config.include = include;
+        }
+        let exclude = with_pattern_files(
+            overrides.exclude,
+            &overrides.exclude_files,
+            "exclude",
+            config.regex,
+        )?;
+        if !exclude.is_empty() {
+            config.exclude = exclude;
+        }
+        if let Some(include_mode) = overrides.include_mode {
+            config.include_mode = include_mode;
+        }
+        if overrides.min_score.is_some() {
+            config.min_score = overrides.min_score;
+        }
+        if let Some(match_field) = overrides.match_field {
+            config.match_field = match_field;
+        }
+        if overrides.classes.is_some() {
+            config.classes = overrides.classes;
+        }
+        if overrides.sender.is_some() {
+            config.sender = overrides.sender;
+        }
+        if overrides.after.is_some() {
+            config.after = overrides.after;
+        }
+        if overrides.before.is_some() {
+            config.before = overrides.before;
         }
+        if overrides.require_doc_regex.is_some() {
+            config.require_doc_regex = overrides.require_doc_regex;
+        }
+        config.prepare()?;
 
         Ok(config)
     }
 
+    /// Sets the denylist config. Any message matching it is dropped, regardless of this config's patterns.
+    pub fn with_deny_config(mut self, deny_config: Config) -> Self {
+        self.deny_config = Some(Box::new(deny_config));
+        self
+    }
+
+    /// Whether documents are checked against the required document regex
+    pub fn has_document_regex(&self) -> bool {
+        self.document_regex.is_some()
+    }
+
+    /// Checks the whole document against the required document regex, if any
+    pub fn matches_document<T: AsRef<str>>(&self, document: T) -> bool {
+        self.document_regex
+            .as_ref()
+            .is_none_or(|document_regex| document_regex.is_match(document.as_ref()))
+    }
+
+    /// Checks whether the message should be kept. Messages matching the deny config are always dropped, otherwise:
+    ///
+    /// | includes | excludes | message is kept if                          |
+    /// |----------|----------|---------------------------------------------|
+    /// | set      | empty    | any include matches                         |
+    /// | empty    | set      | no exclude matches                          |
+    /// | set      | set      | any include matches and no exclude matches  |
+    /// | empty    | empty    | never, an error is returned (`validate`)    |
+    ///
+    /// With the `all` include mode, every include has to match instead of any of them. With `min_score`, the weights of
+    /// the matching includes have to add up to at least the score instead. With the `text` match field,
+    /// patterns are matched against the visible text of the message instead of its markup. Patterns scoped to a class,
+    /// like "class:ooc/test", match only messages with that class, so other messages are neither included nor
+    /// excluded by them.
+    ///
+    /// If `classes` or `sender` are set, messages without any of the classes or from other senders are dropped too,
+    /// and the config without patterns is valid, keeping messages by their markup alone. Messages without a class
+    /// attribute never have any of the classes, and messages without a sender, like system ones, are never from it.
+    /// The same goes for `after` and `before`, except that messages without a timestamp are kept, unless
+    /// `require_timestamp` is set.
+    ///
+    /// If `invert` is set, the kept and dropped messages swap places. The deny config and the error for missing
+    /// patterns aren't affected by it, so denied messages are still dropped and a config without patterns is still
+    /// invalid.
     pub fn matches<T: AsRef<str>>(&self, haystack: T) -> Result<bool, anyhow::Error> {
-        let haystack = if self.match_case {
-            haystack.as_ref().to_string()
-        } else {
-            haystack.as_ref().to_lowercase()
-        };
+        if self.is_denied(haystack.as_ref())? {
+            return Ok(false);
+        }
+
+        let message = haystack.as_ref();
+        let matches_markup = self.matches_markup(message);
+        let haystack = self.normalize_haystack(message)?;
+        Ok((matches_markup
+            && self.includes(&haystack, Some(message))
+            && !self.excludes(&haystack, message))
+            != self.invert)
+    }
+
+    /// Checks only the include patterns against `text`, which is already the visible text, so it is matched as is
+    /// regardless of the match field. Used when the includes are matched against several messages joined together,
+    /// which have no class, so patterns scoped to a class never match them.
+    pub fn matches_include_text<T: AsRef<str>>(&self, text: T) -> Result<bool, anyhow::Error> {
+        self.validate()?;
+        Ok(self.includes(&self.normalize_case(Cow::Borrowed(text.as_ref())), None))
+    }
+
+    /// Checks whether the message should be kept like `matches`, but with the include patterns replaced by whether the
+    /// message is in a window matching them
+    pub fn matches_in_window<T: AsRef<str>>(
+        &self,
+        haystack: T,
+        in_matching_window: bool,
+    ) -> Result<bool, anyhow::Error> {
+        if self.is_denied(haystack.as_ref())? {
+            return Ok(false);
+        }
 
-        if self.exclude.is_none() && self.include.is_none() {
+        let message = haystack.as_ref();
+        let matches_markup = self.matches_markup(message);
+        let haystack = self.normalize_haystack(message)?;
+        Ok(
+            (matches_markup && in_matching_window && !self.excludes(&haystack, message))
+                != self.invert,
+        )
+    }
+
+    fn is_denied(&self, haystack: &str) -> Result<bool, anyhow::Error> {
+        match &self.deny_config {
+            Some(deny_config) => deny_config.matches(haystack),
+            None => Ok(false),
+        }
+    }
+
+    /// Checks that the config is usable for matching messages
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.exclude.is_empty()
+            && self.include.is_empty()
+            && self.classes.is_none()
+            && self.sender.is_none()
+            && self.after.is_none()
+            && self.before.is_none()
+        {
             Err(anyhow::Error::msg(
-                "no exclude/include patterns were provided",
+                "no exclude/include patterns, classes, sender or time bounds were provided",
             ))?
         }
+        if let Some(deny_config) = &self.deny_config {
+            deny_config
+                .validate()
+                .map_err(|err| anyhow::format_err!("invalid deny config: {}", err))?;
+        }
+        Ok(())
+    }
+
+    /// Regexes finding the parts of a text matched by the include patterns: the compiled regexes in regex mode, or the
+    /// escaped patterns otherwise. Case is ignored unless `match_case` is set.
+    pub fn include_finders(&self) -> anyhow::Result<Vec<Regex>> {
+        if self.regex {
+            return Ok(self.include_regexes.clone());
+        }
+        let escaped: Vec<String> = self
+            .include_patterns
+            .iter()
+            .map(|scoped| regex::escape(&scoped.pattern))
+            .collect();
+        compile_patterns(&escaped, "include", self.match_case)
+    }
+
+    fn normalize_haystack<'a>(&self, haystack: &'a str) -> Result<Cow<'a, str>, anyhow::Error> {
+        self.validate()?;
+
+        let haystack = match self.match_field {
+            MatchField::Full => Cow::Borrowed(haystack),
+            MatchField::Text => Cow::Owned(plain_text(haystack)),
+        };
+        Ok(self.normalize_case(haystack))
+    }
+
+    /// Lowercases the haystack for substring patterns, which are lowercased too unless case is matched
+    fn normalize_case<'a>(&self, haystack: Cow<'a, str>) -> Cow<'a, str> {
+        if self.match_case || self.regex {
+            haystack
+        } else {
+            Cow::Owned(haystack.to_lowercase())
+        }
+    }
+
+    /// Checks the include patterns against the normalized `haystack` of `message`. Without a message, patterns scoped
+    /// to a class don't match.
+    fn includes(&self, haystack: &str, message: Option<&str>) -> bool {
+        if self.include_patterns.is_empty() {
+            return true;
+        }
+        // regexes are compiled from the patterns in the same order
+        let include_matches = |index: usize| {
+            let scoped = &self.include_patterns[index];
+            let in_scope = match message {
+                Some(message) => scoped.is_in_scope(message, self.class_case),
+                None => scoped.class.is_none(),
+            };
+            in_scope
+                && if self.regex {
+                    self.include_regexes[index].is_match(haystack)
+                } else {
+                    haystack.contains(scoped.pattern.as_str())
+                }
+        };
+        if let Some(min_score) = self.min_score {
+            let score: u32 = (0..self.include_patterns.len())
+                .filter(|index| include_matches(*index))
+                .map(|index| self.include_patterns[index].weight)
+                .sum();
+            return score >= min_score;
+        }
+        match self.include_mode {
+            IncludeMode::Any => (0..self.include_patterns.len()).any(include_matches),
+            IncludeMode::All => (0..self.include_patterns.len()).all(include_matches),
+        }
+    }
+
+    /// Checks the filters on the markup of the message rather than on its text: its classes, its sender and its
+    /// timestamp
+    fn matches_markup(&self, message: &str) -> bool {
+        let has_class = self.classes.as_ref().is_none_or(|classes| {
+            message_classes(message).any(|class| {
+                classes
+                    .iter()
+                    .any(|wanted| same_name(class, wanted, self.class_case))
+            })
+        });
+        let from_sender = self.sender.as_ref().is_none_or(|wanted| {
+            message_sender(message)
+                .is_some_and(|sender| same_name(&sender, wanted, self.speaker_case))
+        });
+        let in_time_range = (self.after.is_none() && self.before.is_none())
+            || match message_timestamp(message) {
+                Some(timestamp) => {
+                    self.after.is_none_or(|after| timestamp >= after)
+                        && self.before.is_none_or(|before| timestamp <= before)
+                }
+                None => !self.require_timestamp,
+            };
+        has_class && from_sender && in_time_range
+    }
+
+    /// Checks the exclude patterns against the normalized `haystack` of `message`
+    fn excludes(&self, haystack: &str, message: &str) -> bool {
+        self.exclude_patterns
+            .iter()
+            .enumerate()
+            .any(|(index, scoped)| {
+                scoped.is_in_scope(message, self.class_case)
+                    && if self.regex {
+                        self.exclude_regexes[index].is_match(haystack)
+                    } else {
+                        haystack.contains(scoped.pattern.as_str())
+                    }
+            })
+    }
+}
+
+/// Include or exclude pattern along with the class it is scoped to, if it had a "class:NAME/" prefix, and its weight
+/// given by a ":WEIGHT" suffix
+#[derive(Debug)]
+struct ScopedPattern {
+    class: Option<String>,
+    pattern: String,
+    weight: u32,
+}
+
+impl ScopedPattern {
+    /// Splits the class scope from the pattern, and the weight too if the pattern is `weighted`. Patterns without a
+    /// weight, or with a suffix which isn't a number, weigh 1.
+    fn parse(pattern: &str, weighted: bool) -> Self {
+        let (class, pattern) = split_class_scope(pattern);
+        let (pattern, weight) = pattern
+            .rsplit_once(':')
+            .filter(|_| weighted)
+            .and_then(|(pattern, weight)| Some((pattern, weight.parse().ok()?)))
+            .unwrap_or((pattern, 1));
+        Self {
+            class: class.map(str::to_string),
+            pattern: pattern.to_string(),
+            weight,
+        }
+    }
+
+    fn parse_all(patterns: &[String], weighted: bool) -> Vec<Self> {
+        patterns
+            .iter()
+            .map(|pattern| Self::parse(pattern, weighted))
+            .collect()
+    }
+
+    /// The patterns without their scopes, in the same order
+    fn patterns(scoped: &[Self]) -> Vec<String> {
+        scoped.iter().map(|scoped| scoped.pattern.clone()).collect()
+    }
+
+    /// Whether the pattern applies to the message: it is unscoped, or the message has the class it is scoped to
+    fn is_in_scope(&self, message: &str, class_case: bool) -> bool {
+        self.class.as_ref().is_none_or(|wanted| {
+            message_classes(message).any(|class| same_name(class, wanted, class_case))
+        })
+    }
+}
 
-        if let Some(include_regex) = &self.include_regex {
-            if !include_regex.is_match(&haystack) {
-                return Ok(false);
+/// Lowercases the pattern, but not the class it is scoped to, which is matched by `class_case`
+fn lowercase_pattern(pattern: &str) -> String {
+    match split_class_scope(pattern) {
+        (Some(class), unscoped) => {
+            format!(
+                "{}{}/{}",
+                CLASS_SCOPE_PREFIX,
+                class,
+                unscoped.to_lowercase()
+            )
+        }
+        (None, _) => pattern.to_lowercase(),
+    }
+}
+
+/// Splits a pattern like "class:ooc/test" into its class and the pattern itself. Patterns without the prefix, or with
+/// an empty class, are unscoped.
+fn split_class_scope(pattern: &str) -> (Option<&str>, &str) {
+    pattern
+        .strip_prefix(CLASS_SCOPE_PREFIX)
+        .and_then(|scoped| scoped.split_once('/'))
+        .filter(|(class, _)| !class.is_empty())
+        .map_or((None, pattern), |(class, pattern)| (Some(class), pattern))
+}
+
+/// Compares names of classes or senders, ignoring their case unless `match_case` is set
+fn same_name(name: &str, wanted: &str, match_case: bool) -> bool {
+    if match_case {
+        name == wanted
+    } else {
+        name.to_lowercase() == wanted.to_lowercase()
+    }
+}
+
+/// Appends the patterns read from `files` to `patterns`. Every line of a file is a pattern, except for blank lines and
+/// "#" comments. In regex mode each line is compiled right away, so that an invalid one is reported with its line
+/// number.
+fn with_pattern_files(
+    mut patterns: Vec<String>,
+    files: &[PathBuf],
+    kind: &str,
+    regex: bool,
+) -> anyhow::Result<Vec<String>> {
+    for file in files {
+        let contents = read_to_string(file).map_err(|err| {
+            anyhow::format_err!(
+                "failed to read {} patterns from {}: {}",
+                kind,
+                file.to_string_lossy(),
+                err
+            )
+        })?;
+        for (line_index, line) in contents.lines().enumerate() {
+            let trimmed_line = line.trim();
+            if trimmed_line.is_empty() || trimmed_line.starts_with('#') {
+                continue;
             }
-        } else if let Some(include) = &self.include
-            && !haystack.contains(include)
-        {
-            return Ok(false);
+            if regex {
+                Regex::new(split_class_scope(line).1).map_err(|err| {
+                    anyhow::format_err!(
+                        "invalid {} regex on line {} of {}: {}",
+                        kind,
+                        line_index + 1,
+                        file.to_string_lossy(),
+                        err
+                    )
+                })?;
+            }
+            patterns.push(line.to_string());
         }
+    }
+    Ok(patterns)
+}
+
+/// Deserializes either a single pattern or a list of patterns
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(pattern) => vec![pattern],
+        OneOrMany::Many(patterns) => patterns,
+    })
+}
+
+/// Compiles every pattern, naming the failed one by its position in the list
+fn compile_patterns(
+    patterns: &[String],
+    kind: &str,
+    match_case: bool,
+) -> anyhow::Result<Vec<Regex>> {
+    patterns
+        .iter()
+        .enumerate()
+        .map(|(index, pattern)| {
+            RegexBuilder::new(pattern)
+                .case_insensitive(!match_case)
+                .build()
+                .map_err(|err| {
+                    anyhow::format_err!(
+                        "failed to compile {} regex #{} from {}: {}",
+                        kind,
+                        index + 1,
+                        pattern,
+                        err
+                    )
+                })
+        })
+        .collect()
+}
+
+/// Rejects patterns with potentially expensive constructs: unbounded repetitions, repetitions with bounds over
+/// `SAFE_REGEX_MAX_REPETITION` and repetitions nested into other repetitions (`?` is allowed everywhere).
+fn validate_safe_regex(pattern: &str) -> anyhow::Result<()> {
+    let hir = regex_syntax::Parser::new()
+        .parse(pattern)
+        .map_err(|err| anyhow::format_err!("failed to parse regex {}: {}", pattern, err))?;
 
-        if let Some(exclude_regex) = &self.exclude_regex {
-            if exclude_regex.is_match(&haystack) {
-                return Ok(false);
+    check_safe_hir(&hir, false).map_err(|reason| {
+        anyhow::format_err!(
+            "regex {} is not allowed in safe regex mode: {}",
+            pattern,
+            reason
+        )
+    })
+}
+
+fn check_safe_hir(hir: &Hir, in_repetition: bool) -> Result<(), String> {
+    match hir.kind() {
+        HirKind::Repetition(repetition) => {
+            let Some(max) = repetition.max else {
+                return Err(format!(
+                    "unbounded repetition (\"*\", \"+\" or \"{{n,}}\") is not allowed, use a bounded one like \"{{0,{}}}\"",
+                    SAFE_REGEX_MAX_REPETITION
+                ));
+            };
+            if max > SAFE_REGEX_MAX_REPETITION {
+                return Err(format!(
+                    "repetition bound {} exceeds the maximum of {}",
+                    max, SAFE_REGEX_MAX_REPETITION
+                ));
             }
-        } else if let Some(exclude) = &self.exclude
-            && haystack.contains(exclude)
-        {
-            return Ok(false);
+            if max > 1 && in_repetition {
+                return Err("nested repetition is not allowed".to_string());
+            }
+            check_safe_hir(&repetition.sub, in_repetition || max > 1)
+        }
+        HirKind::Capture(capture) => check_safe_hir(&capture.sub, in_repetition),
+        HirKind::Concat(subs) | HirKind::Alternation(subs) => subs
+            .iter()
+            .try_for_each(|sub| check_safe_hir(sub, in_repetition)),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_files::TestFiles;
+
+    fn config(include: &[&str], exclude: &[&str], invert: bool) -> Config {
+        Config::from_args(ConfigArgs {
+            include: include.iter().map(|pattern| pattern.to_string()).collect(),
+            exclude: exclude.iter().map(|pattern| pattern.to_string()).collect(),
+            invert,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn include_only_keeps_included() {
+        let config = config(&["nuke"], &[], false);
+        assert!(config.matches("the nuke is armed").unwrap());
+        assert!(!config.matches("all clear").unwrap());
+    }
+
+    #[test]
+    fn exclude_only_keeps_everything_not_excluded() {
+        let config = config(&[], &["ooc"], false);
+        assert!(config.matches("the nuke is armed").unwrap());
+        assert!(config.matches("all clear").unwrap());
+        assert!(!config.matches("ooc: hi").unwrap());
+    }
+
+    #[test]
+    fn include_and_exclude_combine_with_and() {
+        let config = config(&["nuke"], &["ooc"], false);
+        assert!(config.matches("the nuke is armed").unwrap());
+        assert!(!config.matches("ooc: the nuke is armed").unwrap());
+        assert!(!config.matches("ooc: all clear").unwrap());
+        assert!(!config.matches("all clear").unwrap());
+    }
+
+    #[test]
+    fn no_patterns_is_an_error() {
+        let config = config(&[], &[], false);
+        assert!(config.matches("all clear").is_err());
+    }
+
+    #[test]
+    fn invert_swaps_kept_and_dropped() {
+        let inverted = config(&["nuke"], &["ooc"], true);
+        assert!(!inverted.matches("the nuke is armed").unwrap());
+        assert!(inverted.matches("ooc: the nuke is armed").unwrap());
+        assert!(inverted.matches("all clear").unwrap());
+
+        let exclude_only =
+            config(&[], &["ooc"], true).with_deny_config(config(&["secret"], &[], false));
+        assert!(exclude_only.matches("ooc: hi").unwrap());
+        assert!(!exclude_only.matches("all clear").unwrap());
+        // denied messages stay dropped
+        assert!(!exclude_only.matches("ooc: secret").unwrap());
+
+        // and a config without patterns stays invalid
+        assert!(config(&[], &[], true).matches("all clear").is_err());
+    }
+
+    fn regex_config(include: &str, match_case: bool) -> Config {
+        Config::from_args(ConfigArgs {
+            regex: true,
+            include: vec![include.to_string()],
+            match_case,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn regex_uppercase_class_ignores_case() {
+        let config = regex_config("[A-Z]OHN", false);
+        assert!(config.matches("john says hi").unwrap());
+        assert!(config.matches("JOHN says hi").unwrap());
+
+        let config = regex_config("[A-Z]OHN", true);
+        assert!(!config.matches("john says hi").unwrap());
+        assert!(config.matches("JOHN says hi").unwrap());
+    }
+
+    #[test]
+    fn regex_lowercase_class_ignores_case() {
+        let config = regex_config(r"\b[a-z]dmin\b", false);
+        assert!(config.matches("ADMIN PM").unwrap());
+        assert!(config.matches("admin pm").unwrap());
+
+        let config = regex_config(r"\b[a-z]dmin\b", true);
+        assert!(!config.matches("ADMIN PM").unwrap());
+        assert!(config.matches("admin pm").unwrap());
+    }
+
+    #[test]
+    fn class_scoped_patterns_match_only_their_class() {
+        let ooc = "<div class=\"ChatMessage OOC\">OOC: test the nuke</div>";
+        let say = "<div class=\"ChatMessage say\">John says, \"test the nuke\"</div>";
+        let excluded = config(&[], &["class:ooc/TEST"], false);
+        assert!(!excluded.matches(ooc).unwrap());
+        assert!(excluded.matches(say).unwrap());
+
+        let included = config(&["class:say/nuke"], &[], false);
+        assert!(!included.matches(ooc).unwrap());
+        assert!(included.matches(say).unwrap());
+        // joined windows have no class
+        assert!(!included.matches_include_text("test the nuke").unwrap());
+
+        let regex = Config::from_args(ConfigArgs {
+            regex: true,
+            include: vec!["class:ooc/t[aeiou]st".to_string(), "class:/x".to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(regex.matches(ooc).unwrap());
+        assert!(!regex.matches(say).unwrap());
+        // an empty class isn't a scope
+        assert!(regex.matches("class:/x").unwrap());
+    }
+
+    #[test]
+    fn weights_of_matching_includes_add_up_to_the_score() {
+        let config = Config::from_args(ConfigArgs {
+            include: ["breach:3", "medbay", "hull:2", "12:xx"]
+                .iter()
+                .map(|pattern| pattern.to_string())
+                .collect(),
+            min_score: Some(4),
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(config.matches("breach in medbay").unwrap());
+        assert!(config.matches("hull breach").unwrap());
+        assert!(!config.matches("breach").unwrap());
+        assert!(!config.matches("hull at medbay").unwrap());
+        // suffixes, which aren't numbers, are part of the pattern
+        assert!(config.matches("hull 12:xx at medbay").unwrap());
+    }
+
+    #[test]
+    fn load_lowercases_substring_patterns() {
+        let files = TestFiles::new();
+        let path = files.write(
+            "mixed_case.toml",
+            "regex = false\nmatch_case = false\ninclude = \"Syndicate\"\nexclude = [\"OOC\"]\n",
+        );
+        let config = Config::load(path).unwrap();
+        assert!(config.matches("a syndicate agent").unwrap());
+        assert!(config.matches("A SYNDICATE AGENT").unwrap());
+        assert!(!config.matches("ooc: a syndicate agent").unwrap());
+    }
+
+    #[test]
+    fn load_picks_format_by_extension() {
+        let files = TestFiles::new();
+        let toml = files.write(
+            "format.toml",
+            "regex = false\nmatch_case = false\ninclude = [\"nuke\"]\n",
+        );
+        let json = files.write(
+            "format.json",
+            r#"{"regex": false, "match_case": false, "include": ["nuke"]}"#,
+        );
+        let yaml = files.write(
+            "format.yaml",
+            "regex: false\nmatch_case: false\ninclude: [nuke]\n",
+        );
+        let yml = files.write(
+            "format.YML",
+            "regex: false\nmatch_case: false\ninclude: nuke\n",
+        );
+        for path in [toml, json, yaml, yml] {
+            let config = Config::load(&path).unwrap();
+            assert!(config.matches("the nuke is armed").unwrap(), "{:?}", path);
+            assert!(!config.matches("all clear").unwrap(), "{:?}", path);
         }
+    }
+
+    #[test]
+    fn load_tries_every_format_for_unknown_extension() {
+        let files = TestFiles::new();
+        let json = files.write(
+            "format.conf",
+            r#"{"regex": false, "match_case": false, "include": "nuke"}"#,
+        );
+        assert!(Config::load(json).unwrap().matches("the nuke").unwrap());
+
+        let invalid = files.write("invalid.conf", "regex = [");
+        let err = Config::load(invalid).unwrap_err().to_string();
+        assert!(err.contains("as TOML") && err.contains("as JSON") && err.contains("as YAML"));
+    }
+
+    #[test]
+    fn load_reports_the_format_of_known_extension() {
+        let files = TestFiles::new();
+        let invalid = files.write("invalid.json", "regex = false");
+        let err = Config::load(invalid).unwrap_err().to_string();
+        assert!(err.contains("as JSON"), "{}", err);
+    }
+
+    #[test]
+    fn deny_config_is_serialized() {
+        let denied =
+            config(&["nuke"], &[], false).with_deny_config(config(&["secret"], &[], false));
+
+        let toml = toml::to_string(&denied).unwrap();
+        assert!(toml.contains("[deny_config]"), "{}", toml);
+        let json = serde_json::to_value(&denied).unwrap();
+        assert_eq!(
+            json["deny_config"]["include"],
+            serde_json::json!(["secret"])
+        );
 
-        Ok(true)
+        let plain = serde_json::to_value(config(&["nuke"], &[], false)).unwrap();
+        assert!(plain.get("deny_config").is_none());
     }
 }
@@ -0,0 +1,241 @@
+use std::{
+    fs::{File, read},
+    io::{self, BufRead, BufReader, ErrorKind, Read},
+    path::Path,
+    thread::sleep,
+    time::Duration,
+};
+
+use chardetng::{EncodingDetector, Iso2022JpDetection, Utf8Detection};
+use flate2::read::MultiGzDecoder;
+
+/// First bytes of every gzip stream
+pub(crate) const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Options of reading a log file
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ReadOptions {
+    /// Number of retries of reading the file
+    pub retries: u32,
+    /// Whether to detect the encoding instead of assuming UTF-8
+    pub detect_encoding: bool,
+    /// Whether logs, which aren't valid UTF-8, fail instead of being decoded lossily
+    pub strict: bool,
+}
+
+/// Log read into memory along with how it was decoded
+#[derive(Debug)]
+pub struct DecodedLog {
+    pub chat_log: String,
+    /// Encoding of the log, if it was detected
+    pub encoding: Option<&'static str>,
+    /// Whether the log wasn't valid UTF-8, so invalid bytes were replaced
+    pub lossy: bool,
+}
+
+/// Reads the whole log at `path`, decompressing it if it is gzipped. Logs, which aren't valid UTF-8, are decoded
+/// lossily, replacing invalid bytes, unless the mode is strict, in which case they fail.
+pub fn read_chat_log<T: AsRef<Path>>(
+    path: T,
+    options: ReadOptions,
+) -> Result<DecodedLog, anyhow::Error> {
+    let path = path.as_ref();
+    if options.detect_encoding {
+        let bytes = read_with_retries(path, options.retries, read_decompressed)
+            .map_err(|err| anyhow::format_err!("error while reading the input file: {}", err))?;
+        let (chat_log, encoding) = decode_detected(&bytes);
+        return Ok(DecodedLog {
+            chat_log,
+            encoding: Some(encoding),
+            lossy: false,
+        });
+    }
+
+    let read = read_with_retries(path, options.retries, |path| {
+        String::from_utf8(read_decompressed(path)?).map_err(|_| {
+            io::Error::new(ErrorKind::InvalidData, "stream did not contain valid UTF-8")
+        })
+    });
+    let (chat_log, lossy) = match read {
+        Ok(chat_log) => (chat_log, false),
+        Err(err) if err.kind() == ErrorKind::InvalidData && !options.strict => {
+            let bytes = read_decompressed(path).map_err(|err| {
+                anyhow::format_err!("error while reading the input file: {}", err)
+            })?;
+            (String::from_utf8_lossy(&bytes).into_owned(), true)
+        }
+        Err(err) => Err(anyhow::format_err!(
+            "error while reading the input file: {}",
+            err
+        ))?,
+    };
+    Ok(DecodedLog {
+        chat_log,
+        encoding: None,
+        lossy,
+    })
+}
+
+/// Opens the log at `path` for streaming, retrying like `read_chat_log`. Gzipped logs are decompressed on the fly.
+pub fn open_chat_log<T: AsRef<Path>>(
+    path: T,
+    retries: u32,
+) -> Result<Box<dyn BufRead>, anyhow::Error> {
+    let path = path.as_ref();
+    let input = read_with_retries(path, retries, |path| File::open(path))
+        .map_err(|err| anyhow::format_err!("error while reading the input file: {}", err))?;
+    let gzipped = path.extension().is_some_and(|extension| extension == "gz");
+    Ok(decompressed(BufReader::new(input), gzipped))
+}
+
+/// Wraps `reader` into a gzip decoder if `gzipped` is set or the input starts with the gzip magic bytes
+pub fn decompressed<'a>(mut reader: impl BufRead + 'a, gzipped: bool) -> Box<dyn BufRead + 'a> {
+    let gzipped = gzipped
+        || reader
+            .fill_buf()
+            .is_ok_and(|buf| buf.starts_with(&GZIP_MAGIC));
+    if gzipped {
+        Box::new(BufReader::new(MultiGzDecoder::new(reader)))
+    } else {
+        Box::new(reader)
+    }
+}
+
+/// Reads the file at `path`, retrying up to `retries` times on errors which may be caused by the file being locked or
+/// written to at the moment. Delay before each retry is doubled, starting from 100ms.
+fn read_with_retries<T>(
+    path: &Path,
+    retries: u32,
+    read: impl Fn(&Path) -> io::Result<T>,
+) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match read(path) {
+            Ok(contents) => return Ok(contents),
+            Err(err) if attempt < retries && is_transient_read_error(&err) => {
+                let delay = Duration::from_millis(100 << attempt.min(10));
+                attempt += 1;
+                eprintln!(
+                    "Failed to read {}: {}. Retrying in {}ms ({}/{})",
+                    path.to_string_lossy(),
+                    err,
+                    delay.as_millis(),
+                    attempt,
+                    retries
+                );
+                sleep(delay);
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Reads the file at `path`, decompressing it if it is gzipped. Gzipped files are recognized either by the ".gz"
+/// extension or by the gzip magic bytes.
+fn read_decompressed(path: &Path) -> io::Result<Vec<u8>> {
+    let bytes = read(path)?;
+    let gzipped = path.extension().is_some_and(|extension| extension == "gz")
+        || bytes.starts_with(&GZIP_MAGIC);
+    if !gzipped {
+        return Ok(bytes);
+    }
+
+    let mut decompressed = Vec::new();
+    MultiGzDecoder::new(bytes.as_slice()).read_to_end(&mut decompressed)?;
+    Ok(decompressed)
+}
+
+/// Decodes `bytes` with the encoding guessed from their contents. A BOM, if present, takes precedence over the guess.
+/// Returns the decoded text and the name of the encoding used.
+fn decode_detected(bytes: &[u8]) -> (String, &'static str) {
+    let mut detector = EncodingDetector::new(Iso2022JpDetection::Deny);
+    detector.feed(bytes, true);
+    let (text, encoding, _) = detector.guess(None, Utf8Detection::Allow).decode(bytes);
+    (text.into_owned(), encoding.name())
+}
+
+/// Whether the error may go away on its own, like a lock held by the program writing the file. Missing files and
+/// invalid contents aren't retried, since they are far more likely to be a mistyped path or a log in another encoding
+/// than a file caught mid-write, and retrying them would only delay the error.
+fn is_transient_read_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::PermissionDenied
+            | ErrorKind::WouldBlock
+            | ErrorKind::Interrupted
+            | ErrorKind::TimedOut
+            | ErrorKind::ResourceBusy
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, time::Instant};
+
+    use flate2::{Compression, write::GzEncoder};
+
+    use super::*;
+    use crate::test_files::TestFiles;
+
+    fn gzipped(contents: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(contents).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn gzipped_logs_are_decompressed() {
+        let files = TestFiles::new();
+        // recognized by the magic bytes, regardless of the extension
+        let path = files.write("game.html", gzipped(b"<div>nuke</div>"));
+        let decoded = read_chat_log(&path, ReadOptions::default()).unwrap();
+        assert_eq!(decoded.chat_log, "<div>nuke</div>");
+
+        let mut streamed = String::new();
+        open_chat_log(&path, 0)
+            .unwrap()
+            .read_to_string(&mut streamed)
+            .unwrap();
+        assert_eq!(streamed, "<div>nuke</div>");
+    }
+
+    #[test]
+    fn invalid_utf8_is_lossy_unless_strict() {
+        let files = TestFiles::new();
+        let path = files.write("latin1.html", b"<div>caf\xe9</div>");
+        let decoded = read_chat_log(&path, ReadOptions::default()).unwrap();
+        assert_eq!(decoded.chat_log, "<div>caf\u{fffd}</div>");
+        assert!(decoded.lossy);
+
+        let strict = ReadOptions {
+            strict: true,
+            ..Default::default()
+        };
+        assert!(read_chat_log(&path, strict).is_err());
+
+        let detect = ReadOptions {
+            detect_encoding: true,
+            ..Default::default()
+        };
+        let decoded = read_chat_log(&path, detect).unwrap();
+        assert_eq!(decoded.chat_log, "<div>café</div>");
+        assert!(decoded.encoding.is_some() && !decoded.lossy);
+    }
+
+    #[test]
+    fn missing_and_invalid_files_are_not_retried() {
+        let files = TestFiles::new();
+        let retrying = ReadOptions {
+            retries: 4,
+            strict: true,
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let path = files.write("latin1_strict.html", b"<div>caf\xe9</div>");
+        assert!(read_chat_log(&path, retrying).is_err());
+        assert!(read_chat_log(files.path().join("missing.html"), retrying).is_err());
+        assert!(open_chat_log(files.path().join("missing.html"), 4).is_err());
+        // the first retry alone would take 100ms
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,1705 @@
+//! Filtering of the Space Station 13 saved chat logs, which the ss13_mlogfilter CLI is built on
+
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::{self, Display},
+    io::{BufReader, Read, Write},
+    ops::{AddAssign, Range},
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+
+pub use crate::{
+    anonymizer::Anonymizer,
+    config::{Config, ConfigArgs, DEFAULT_CONFIG, IncludeMode, MatchField},
+    parser::{BuiltinParser, LogParts, MessageParser, Segment},
+    parsing::{ChatMessage, plain_text},
+    timestamp::Timestamp,
+};
+
+mod anonymizer;
+/// Command line arguments of the ss13_mlogfilter CLI and the config built from them
+pub mod cli;
+mod config;
+/// Reading of chat log files: decompression, decoding and retries
+pub mod input;
+/// Splitting of chat logs saved by different SS13 forks into messages
+pub mod parser;
+mod parsing;
+/// Routing of kept messages to destinations other than the filtered log
+pub mod sink;
+/// Filtering of chat logs read message by message
+pub mod stream;
+/// Synthetic chat logs resembling the ones saved by SS13 clients, for benchmarks and tests
+pub mod synthetic;
+#[cfg(test)]
+mod test_files;
+mod timestamp;
+
+/// Opening tag of the chat log contents, which go after the header
+pub const CHAT_OPENING: &str = "<div class=\"Chat\">";
+
+/// Beginning of the opening tag of every chat message of the tg logs. Left unterminated, since more classes may follow,
+/// e.g. `<div class="ChatMessage say">`.
+const MESSAGE_MARKER: &str = "<div class=\"ChatMessage";
+
+/// Tags wrapping the table of contents of kept messages
+const TOC_OPENING: &str = "<div class=\"ChatToc\"><ol>";
+const TOC_CLOSING: &str = "</ol></div>\n";
+
+/// Closing tags of the chat log after the last message
+const CHAT_LOG_FOOTER: &str = "</div>\n</body>\n</html>";
+
+/// Format of the filtered logs
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Kept messages along with the original header and footer
+    #[default]
+    Html,
+    /// Visible text of kept messages, one message per line
+    Plain,
+    /// Array of kept messages with their HTML, visible text and classes
+    Json,
+    /// Kept messages with their HTML, visible text and classes, one JSON object per line. Unlike the JSON array, it
+    /// can be streamed.
+    Jsonl,
+}
+
+impl OutputFormat {
+    /// Extension of files in this format, without the leading dot
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Html => "html",
+            Self::Plain => "txt",
+            Self::Json => "json",
+            Self::Jsonl => "jsonl",
+        }
+    }
+}
+
+/// Third of the round's time span, from its first to its last timestamp
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Phase {
+    /// First third of the round
+    Early,
+    /// Middle third of the round
+    Mid,
+    /// Last third of the round, up to its last timestamp
+    Late,
+}
+
+/// Options of filtering a single log, which are not part of the `Config`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FilterOptions<'a> {
+    /// Maximum number of kept messages, after which the rest of the log is skipped
+    pub limit: Option<usize>,
+    /// Number of consecutive messages to match include patterns against at once
+    pub window: Option<usize>,
+    /// Number of messages kept around every matching one
+    pub context: Option<usize>,
+    /// Number of the last matching messages of each sender, which are kept, dropping the earlier ones
+    pub last_per_speaker: Option<usize>,
+    /// Third of the round, which matching messages have to be sent in
+    pub phase: Option<Phase>,
+    /// Maximum size of the output document in bytes
+    pub output_budget: Option<usize>,
+    /// HTML inserted between non-adjacent kept messages
+    pub separator: Option<&'a str>,
+    /// HTML snippet wrapping each kept message of the HTML output, with placeholders filled from the message as
+    /// described by `apply_message_template`
+    pub message_template: Option<&'a str>,
+    /// Whether to remove script blocks from the output
+    pub strip_scripts: bool,
+    /// Whether to remove the timestamps of kept messages
+    pub strip_timestamps: bool,
+    /// Pseudonyms replacing the senders of kept messages
+    pub anonymizer: Option<&'a Anonymizer>,
+    /// Length in bytes, over which messages are skipped without matching
+    pub max_message_length: Option<usize>,
+    /// Whether too long messages fail the log instead of being skipped
+    pub strict: bool,
+    /// Whether to prepend a table of contents of kept messages
+    pub toc: bool,
+    /// Format of the output
+    pub format: OutputFormat,
+    /// Whether to pretty-print the JSON output
+    pub pretty_json: bool,
+    /// Whether to collapse runs of identical consecutive kept messages
+    pub dedup: bool,
+    /// Whether to mark collapsed messages with the length of their runs
+    pub mark_repeats: bool,
+    /// Parser of the log, or `None` for the tg logs
+    pub parser: Option<&'a dyn MessageParser>,
+}
+
+/// Outcome of filtering a single chat log
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FilterStats {
+    /// Messages in the input log
+    pub total: usize,
+    /// Messages written to the output
+    pub kept: usize,
+    /// Matching messages left out to fit the output budget
+    pub omitted_by_budget: usize,
+    /// Messages collapsed into identical preceding ones
+    pub deduplicated: usize,
+    /// Messages of merged logs left out for being identical to messages of earlier logs
+    pub merge_duplicates: usize,
+    /// Messages skipped for exceeding the maximum message length
+    pub skipped_too_long: usize,
+    /// Size of the input log in bytes after decompression and decoding
+    pub bytes_in: usize,
+    /// Size of the output log in bytes before compression
+    pub bytes_out: usize,
+    /// Line breaks in the output log
+    pub lines_out: usize,
+    /// Whether the log had no chat panes, so the whole document was filtered
+    pub whole_document: bool,
+    /// Encoding of the input, if it was detected by the caller decoding it
+    pub encoding: Option<&'static str>,
+    /// Whether filtering stopped at the limit of kept messages before the end of the log
+    pub limit_reached: bool,
+    /// Whether the log was read only up to the limit of kept messages, so `total` and `bytes_in` count only the part
+    /// read, not the whole log
+    pub read_partially: bool,
+    /// Whether the input wasn't valid UTF-8, so invalid bytes were replaced
+    pub lossy: bool,
+}
+
+/// Time spent in each stage of processing
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StageTimings {
+    pub reading: Duration,
+    pub splitting: Duration,
+    pub matching: Duration,
+    pub writing: Duration,
+}
+
+impl AddAssign for StageTimings {
+    fn add_assign(&mut self, other: Self) {
+        self.reading += other.reading;
+        self.splitting += other.splitting;
+        self.matching += other.matching;
+        self.writing += other.writing;
+    }
+}
+
+impl Display for StageTimings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "reading {:.3}ms, splitting {:.3}ms, matching {:.3}ms, writing {:.3}ms",
+            self.reading.as_secs_f64() * 1000.0,
+            self.splitting.as_secs_f64() * 1000.0,
+            self.matching.as_secs_f64() * 1000.0,
+            self.writing.as_secs_f64() * 1000.0
+        )
+    }
+}
+
+/// Filters messages of the chat log with the default options, keeping the ones matching `config`. Returns the filtered
+/// log.
+pub fn filter_chat_log(chat_log: &str, config: &Config) -> Result<String, anyhow::Error> {
+    let (filtered_chat_log, _) = filter_chat_log_with_options(
+        chat_log,
+        config,
+        FilterOptions::default(),
+        &mut StageTimings::default(),
+    )?;
+    Ok(filtered_chat_log)
+}
+
+/// Filters the chat log from `reader` into `writer` message by message, without loading the whole log into memory.
+/// Gzipped input is decompressed on the fly. Fails if any of `options` needs the whole log (see `can_stream`). Returns
+/// the filter stats.
+pub fn filter_chat_log_streaming(
+    reader: impl Read,
+    writer: impl Write,
+    config: &Config,
+    options: FilterOptions<'_>,
+) -> Result<FilterStats, anyhow::Error> {
+    if !can_stream(config, options) {
+        Err(anyhow::format_err!(
+            "the options need the whole log in memory, so it can't be streamed"
+        ))?
+    }
+    let mut reader = input::decompressed(BufReader::new(reader), false);
+    let header = stream::read_header(&mut reader)?;
+    stream::stream_chat_log(reader, header, writer, config, options)
+}
+
+/// Whether the log can be filtered by `filter_chat_log_streaming` instead of being loaded into memory, which is
+/// impossible if any option needs the whole log
+pub fn can_stream(config: &Config, options: FilterOptions<'_>) -> bool {
+    !config.has_document_regex()
+        && options.window.is_none()
+        && options.context.is_none()
+        && options.last_per_speaker.is_none()
+        && options.phase.is_none()
+        && !options.toc
+        && options.format != OutputFormat::Json
+        && !options.dedup
+        && options.parser.is_none()
+}
+
+/// Filters messages of the chat log with `options`. Returns the filtered log and its stats. Time spent in each stage is
+/// stored in `timings`, except for writing, which is left to the caller.
+///
+/// Messages go through the stages in a fixed order, whichever options are combined:
+/// 1. filter: too long messages are skipped, the rest are matched against `config` along with the window, only the
+///    matches sent in the `phase` of the round are left, then only the last matches of each sender if
+///    `last_per_speaker` is set, and the context is added around them;
+/// 2. dedup: matching messages repeating the last kept one are collapsed into it;
+/// 3. budget: messages, which don't fit the output budget, are omitted along with all later ones;
+/// 4. limit: filtering stops once the limit of kept messages is reached, though later repeats of the last kept
+///    message are still collapsed into it.
+///
+/// Every stage keeps the order of the log, so the output is always a subsequence of it.
+pub fn filter_chat_log_with_options(
+    chat_log: &str,
+    config: &Config,
+    options: FilterOptions<'_>,
+    timings: &mut StageTimings,
+) -> Result<(String, FilterStats), anyhow::Error> {
+    let (filtered_chat_log, stats, _) = filter_messages(chat_log, config, options, timings)?;
+    Ok((filtered_chat_log, stats))
+}
+
+/// Filters the chat log like `filter_chat_log_with_options`, but renders the kept messages in each of `formats` instead
+/// of the format of `options`. Messages are matched once for all of the formats, while the output budget and the limit
+/// apply to each output separately. Table of contents and separators are only added to HTML. Returns the filtered log
+/// and its stats in each format, in the order of `formats`.
+pub fn filter_chat_log_in_formats(
+    chat_log: &str,
+    config: &Config,
+    options: FilterOptions<'_>,
+    formats: &[OutputFormat],
+    timings: &mut StageTimings,
+) -> Result<Vec<(String, FilterStats)>, anyhow::Error> {
+    let filtered = filter_messages_in_formats(chat_log, config, options, formats, timings)?;
+    Ok(filtered
+        .into_iter()
+        .map(|(filtered_chat_log, stats, _)| (filtered_chat_log, stats))
+        .collect())
+}
+
+/// Filters messages of the chat log like `filter_chat_log_with_options`, also returning indices of kept messages
+fn filter_messages(
+    chat_log: &str,
+    config: &Config,
+    options: FilterOptions<'_>,
+    timings: &mut StageTimings,
+) -> Result<(String, FilterStats, Vec<usize>), anyhow::Error> {
+    let mut filtered =
+        filter_messages_in_formats(chat_log, config, options, &[options.format], timings)?;
+    Ok(filtered.remove(0))
+}
+
+/// Filters messages of the chat log into each of `formats` like `filter_chat_log_in_formats`, also returning indices of
+/// kept messages
+fn filter_messages_in_formats(
+    chat_log: &str,
+    config: &Config,
+    options: FilterOptions<'_>,
+    formats: &[OutputFormat],
+    timings: &mut StageTimings,
+) -> Result<Vec<(String, FilterStats, Vec<usize>)>, anyhow::Error> {
+    let splitting_start = Instant::now();
+    let LogParts {
+        header,
+        segments,
+        footer: mut log_footer,
+    } = options.parser().split(chat_log)?;
+    // the log may be cut off, e.g. if it is still being written
+    if log_footer.is_empty() && header.is_some() {
+        log_footer = CHAT_LOG_FOOTER;
+    }
+    let messages: Vec<&str> = segments
+        .iter()
+        .filter_map(|segment| match segment {
+            Segment::Markup(_) => None,
+            Segment::Message(_, message) => Some(*message),
+        })
+        .collect();
+    timings.splitting = splitting_start.elapsed();
+
+    let matching_start = Instant::now();
+    let window_matches = match options.window {
+        Some(window) => {
+            // too long messages take part in windows as empty ones, so they are never matched
+            let matchable_messages: Vec<&str> = messages
+                .iter()
+                .map(|message| {
+                    if options.is_too_long(message) {
+                        ""
+                    } else {
+                        message
+                    }
+                })
+                .collect();
+            Some(match_windows(&matchable_messages, window, config)?)
+        }
+        None => None,
+    };
+    let matches = |index: usize, message: &str| match &window_matches {
+        Some(window_matches) => config.matches_in_window(message, window_matches[index]),
+        None => config.matches(message),
+    };
+    // options selecting messages by the rest of the log need all matches up front, as do several formats, so that
+    // messages are matched once for all of them
+    let context_matches = if options.context.is_some()
+        || options.last_per_speaker.is_some()
+        || options.phase.is_some()
+        || formats.len() > 1
+    {
+        let mut matching = messages
+            .iter()
+            .enumerate()
+            .map(|(index, message)| Ok(!options.is_too_long(message) && matches(index, message)?))
+            .collect::<Result<Vec<bool>, anyhow::Error>>()?;
+        if let Some(phase) = options.phase {
+            let in_phase = messages_in_phase(&messages, phase);
+            matching
+                .iter_mut()
+                .zip(in_phase)
+                .for_each(|(matches, in_phase)| *matches &= in_phase);
+        }
+        if let Some(last_per_speaker) = options.last_per_speaker {
+            matching = last_matches_per_speaker(&messages, &matching, last_per_speaker);
+        }
+        if let Some(context) = options.context {
+            matching = with_context(&matching, context);
+        }
+        Some(matching)
+    } else {
+        None
+    };
+    let kept_by_config = |index: usize, message: &str| match &context_matches {
+        Some(context_matches) => Ok(context_matches[index]),
+        None => matches(index, message),
+    };
+
+    let log = SplitLog {
+        chat_log,
+        header,
+        segments: &segments,
+        footer: log_footer,
+        message_count: messages.len(),
+    };
+    let filtered = formats
+        .iter()
+        .map(|&format| {
+            let is_html = format == OutputFormat::Html;
+            let options = FilterOptions {
+                format,
+                toc: options.toc && is_html,
+                separator: options.separator.filter(|_| is_html),
+                message_template: options.message_template.filter(|_| is_html),
+                ..options
+            };
+            render_messages(log, options, &kept_by_config)
+        })
+        .collect::<Result<Vec<_>, anyhow::Error>>()?;
+    timings.matching = matching_start.elapsed();
+
+    Ok(filtered)
+}
+
+/// Chat log split into its header, segments and footer
+#[derive(Clone, Copy)]
+struct SplitLog<'l, 's> {
+    chat_log: &'l str,
+    header: Option<&'l str>,
+    segments: &'s [Segment<'l>],
+    footer: &'l str,
+    /// Number of message segments
+    message_count: usize,
+}
+
+/// Renders the messages of the split log, which are kept by `kept_by_config`, in the format of `options`, going
+/// through the stages after matching: dedup, budget and limit
+fn render_messages(
+    log: SplitLog<'_, '_>,
+    options: FilterOptions<'_>,
+    kept_by_config: &impl Fn(usize, &str) -> Result<bool, anyhow::Error>,
+) -> Result<(String, FilterStats, Vec<usize>), anyhow::Error> {
+    let is_html = options.format == OutputFormat::Html;
+    let footer = match options.format {
+        OutputFormat::Html => log.footer,
+        OutputFormat::Plain | OutputFormat::Jsonl => "",
+        // brackets of the array
+        OutputFormat::Json => "[]",
+    };
+    let mut output = String::with_capacity(log.chat_log.len());
+    if is_html {
+        output.push_str(&options.sanitize(log.header.unwrap_or_default()));
+    }
+    // kept messages are collected separately, since the table of contents goes before them
+    let mut body = String::with_capacity(log.chat_log.len());
+    let mut json_messages: Vec<ChatMessage> = Vec::new();
+
+    let mut filter = MessageFilter::new(
+        options,
+        footer.len(),
+        FilterStats {
+            total: log.message_count,
+            whole_document: log.header.is_none(),
+            ..Default::default()
+        },
+    );
+    // range of the last kept message in the body
+    let mut last_message = 0..0;
+    // key and index of the last message passed by dedup, with how many times in a row it was repeated since, and
+    // whether it was kept or omitted to fit the output budget
+    let mut last_key = String::new();
+    let mut last_deduped_index = None;
+    let mut repeats = 0;
+    let mut run_kept = false;
+    for segment in log.segments {
+        let (index, message) = match *segment {
+            // markup after the last kept message is skipped once the limit is reached, like when the log is streamed
+            Segment::Markup(_) if filter.is_full() => continue,
+            // other markup, which isn't a message, is always kept
+            Segment::Markup(markup) => {
+                if is_html {
+                    body.push_str(&options.sanitize(markup));
+                }
+                continue;
+            }
+            Segment::Message(index, message) => (index, message),
+        };
+        let verdict = if filter.is_full() {
+            // the limit counts deduplicated messages, so repeats of the last kept message are still collapsed into it
+            let repeats_last_kept = options.dedup
+                && index > 0
+                && last_deduped_index == Some(index - 1)
+                && parsing::without_timestamp(message) == last_key
+                && !options.is_too_long(message)
+                && kept_by_config(index, message)?;
+            if !repeats_last_kept {
+                filter.stats.limit_reached = true;
+                break;
+            }
+            Verdict::Match
+        } else {
+            filter.check(index, message, || kept_by_config(index, message))?
+        };
+        match verdict {
+            // the rest of the log is skipped, closed by its footer
+            Verdict::Stop => break,
+            Verdict::Drop => continue,
+            Verdict::Match => {}
+        }
+        if options.dedup {
+            let key = parsing::without_timestamp(message);
+            if index > 0 && last_deduped_index == Some(index - 1) && key == last_key {
+                repeats += 1;
+                filter.stats.deduplicated += 1;
+                last_deduped_index = Some(index);
+                if run_kept {
+                    filter.last_kept_index = Some(index);
+                }
+                continue;
+            }
+            if options.mark_repeats && run_kept && repeats > 0 {
+                mark_repeated(
+                    &mut body,
+                    last_message.clone(),
+                    &mut json_messages,
+                    options.format,
+                    repeats + 1,
+                );
+            }
+            repeats = 0;
+            last_key = key.into_owned();
+            last_deduped_index = Some(index);
+        }
+        let Some(kept) = filter.keep(index, message, output.len() + body.len())? else {
+            run_kept = false;
+            continue;
+        };
+        run_kept = true;
+        body.push_str(kept.separator);
+        last_message = body.len()..body.len() + kept.message.len();
+        body.push_str(&kept.message);
+        json_messages.extend(kept.json);
+    }
+
+    if options.mark_repeats && run_kept && repeats > 0 {
+        mark_repeated(
+            &mut body,
+            last_message,
+            &mut json_messages,
+            options.format,
+            repeats + 1,
+        );
+    }
+
+    let mut stats = filter.stats;
+    stats.bytes_in = log.chat_log.len();
+
+    if options.format == OutputFormat::Json {
+        let output = if options.pretty_json {
+            serde_json::to_string_pretty(&json_messages)?
+        } else {
+            serde_json::to_string(&json_messages)?
+        };
+        stats.bytes_out = output.len();
+        stats.lines_out = output.matches('\n').count();
+        return Ok((output, stats, filter.kept_indices));
+    }
+
+    if options.toc {
+        output.push_str(TOC_OPENING);
+        output.push_str(&filter.toc_entries);
+        output.push_str(TOC_CLOSING);
+    }
+    output.push_str(&body);
+    output.push_str(footer);
+    stats.bytes_out = output.len();
+    stats.lines_out = output.matches('\n').count();
+
+    Ok((output, stats, filter.kept_indices))
+}
+
+impl FilterOptions<'_> {
+    /// Whether the message exceeds the maximum message length
+    fn is_too_long(&self, message: &str) -> bool {
+        self.max_message_length
+            .is_some_and(|max_message_length| message.len() > max_message_length)
+    }
+
+    /// Applies the options changing the markup of a kept message, regardless of the output format: removes its scripts
+    /// and timestamp, and anonymizes its sender
+    fn prepare<'m>(&self, message: &'m str) -> Cow<'m, str> {
+        let mut message = self.sanitize(message);
+        if self.strip_timestamps
+            && let Cow::Owned(stripped) = parsing::without_timestamp(&message)
+        {
+            message = Cow::Owned(stripped);
+        }
+        if let Some(anonymizer) = self.anonymizer
+            && let Cow::Owned(anonymized) = anonymizer.anonymize(&message)
+        {
+            message = Cow::Owned(anonymized);
+        }
+        message
+    }
+
+    /// Parser of the log, which is the tg one unless the options set another
+    fn parser(&self) -> &dyn MessageParser {
+        self.parser.unwrap_or(&BuiltinParser::Tg)
+    }
+
+    /// Removes script blocks from `html` if the options say so
+    fn sanitize<'h>(&self, html: &'h str) -> Cow<'h, str> {
+        if self.strip_scripts {
+            strip_scripts(html)
+        } else {
+            Cow::Borrowed(html)
+        }
+    }
+}
+
+/// Outcome of checking a message before it is rendered
+enum Verdict {
+    /// The limit of kept messages is reached, so the rest of the log is skipped
+    Stop,
+    /// The message is dropped
+    Drop,
+    /// The message should be kept
+    Match,
+}
+
+/// Message rendered in the output format, which fits the output budget
+struct Kept<'s, 'm> {
+    /// Separator written before the message
+    separator: &'s str,
+    message: Cow<'m, str>,
+    /// The message parsed for the JSON output
+    json: Option<ChatMessage>,
+}
+
+/// Steps applied to every message by both the in-memory and the streamed filtering: the limit of kept messages, the
+/// maximum message length and matching in `check`, then rendering, separators and the output budget in `keep`
+struct MessageFilter<'a> {
+    options: FilterOptions<'a>,
+    /// Length of the footer, which the output budget has to leave room for
+    footer_len: usize,
+    stats: FilterStats,
+    last_kept_index: Option<usize>,
+    /// Indices of kept messages in order
+    kept_indices: Vec<usize>,
+    /// Entries of the table of contents of kept messages
+    toc_entries: String,
+}
+
+impl<'a> MessageFilter<'a> {
+    fn new(options: FilterOptions<'a>, footer_len: usize, stats: FilterStats) -> Self {
+        Self {
+            options,
+            footer_len,
+            stats,
+            last_kept_index: None,
+            kept_indices: Vec::new(),
+            toc_entries: String::new(),
+        }
+    }
+
+    /// Whether the limit of kept messages is reached, so the rest of the log is skipped
+    fn is_full(&self) -> bool {
+        self.options
+            .limit
+            .is_some_and(|limit| self.stats.kept >= limit)
+    }
+
+    /// Checks the message at `index` against the limit of kept messages, the maximum message length and `matches`
+    fn check(
+        &mut self,
+        index: usize,
+        message: &str,
+        matches: impl FnOnce() -> anyhow::Result<bool>,
+    ) -> anyhow::Result<Verdict> {
+        if self.is_full() {
+            self.stats.limit_reached = true;
+            return Ok(Verdict::Stop);
+        }
+        if self.options.is_too_long(message) {
+            if self.options.strict {
+                Err(anyhow::format_err!(
+                    "message {} is {} bytes long, which exceeds the maximum message length",
+                    index + 1,
+                    message.len()
+                ))?
+            }
+            self.stats.skipped_too_long += 1;
+            return Ok(Verdict::Drop);
+        }
+        Ok(if matches()? {
+            Verdict::Match
+        } else {
+            Verdict::Drop
+        })
+    }
+
+    /// Renders the matching message at `index` and counts it as kept, unless it would make the output, which is
+    /// `written` bytes long so far, exceed the budget
+    fn keep<'m>(
+        &mut self,
+        index: usize,
+        message: &'m str,
+        written: usize,
+    ) -> anyhow::Result<Option<Kept<'a, 'm>>> {
+        let mut message = self.options.prepare(message);
+        let mut toc_entry = String::new();
+        if self.options.toc {
+            message = Cow::Owned(with_anchor(&message, index));
+            toc_entry = format!(
+                "<li><a href=\"#message-{}\">{}</a></li>",
+                index + 1,
+                toc_label(&message, index)
+            );
+        }
+        let mut json = None;
+        match self.options.format {
+            OutputFormat::Html => {
+                if let Some(template) = self.options.message_template {
+                    message = Cow::Owned(apply_message_template(template, &message));
+                }
+            }
+            OutputFormat::Plain => message = Cow::Owned(parsing::plain_text(&message) + "\n"),
+            OutputFormat::Json => {
+                let parsed = self.options.parser().parse(&message);
+                // compact serialization with a separating comma, which the budget is checked against
+                message = Cow::Owned(serde_json::to_string(&parsed)? + ",");
+                json = Some(parsed);
+            }
+            OutputFormat::Jsonl => {
+                message = Cow::Owned(
+                    serde_json::to_string(&self.options.parser().parse(&message))? + "\n",
+                );
+            }
+        }
+        let separator = match (self.options.separator, self.last_kept_index) {
+            (Some(separator), Some(last_kept_index)) if index > last_kept_index + 1 => separator,
+            _ => "",
+        };
+
+        // once a message didn't fit, later ones are omitted too, so the output stays a contiguous prefix
+        let exceeds_budget = self.options.output_budget.is_some_and(|budget| {
+            let toc_len = if self.options.toc {
+                TOC_OPENING.len() + self.toc_entries.len() + toc_entry.len() + TOC_CLOSING.len()
+            } else {
+                0
+            };
+            written + toc_len + separator.len() + message.len() + self.footer_len > budget
+        });
+        if self.stats.omitted_by_budget > 0 || exceeds_budget {
+            self.stats.omitted_by_budget += 1;
+            return Ok(None);
+        }
+
+        self.toc_entries.push_str(&toc_entry);
+        self.stats.kept += 1;
+        self.last_kept_index = Some(index);
+        self.kept_indices.push(index);
+        Ok(Some(Kept {
+            separator,
+            message,
+            json,
+        }))
+    }
+}
+
+/// Appends the " (x N)" marker to the text of the last kept message, which is in `last_message` range of the body
+fn mark_repeated(
+    body: &mut String,
+    last_message: Range<usize>,
+    json_messages: &mut [ChatMessage],
+    format: OutputFormat,
+    count: usize,
+) {
+    let marker = format!(" (x {})", count);
+    match format {
+        // text of the message ends before its closing tag
+        OutputFormat::Html => {
+            if let Some(end) = body[last_message.clone()].rfind("</div>") {
+                body.insert_str(last_message.start + end, &marker);
+            }
+        }
+        OutputFormat::Plain => body.insert_str(last_message.end - 1, &marker),
+        // quotes inside the fields are escaped, so the text ends right before the classes field
+        OutputFormat::Jsonl => {
+            if let Some(end) = body[last_message.clone()].rfind("\",\"classes\":[") {
+                body.insert_str(last_message.start + end, &marker);
+            }
+        }
+        OutputFormat::Json => {
+            if let Some(message) = json_messages.last_mut() {
+                message.text.push_str(&marker);
+            }
+        }
+    }
+}
+
+/// Adds an `id` attribute to the message's opening tag, so the table of contents can link to it
+fn with_anchor(message: &str, index: usize) -> String {
+    // the attribute goes right after the tag name, since the class attribute may go on with more classes
+    let tag_name_len = "<div".len();
+    format!(
+        "{} id=\"message-{}\"{}",
+        &message[..tag_name_len],
+        index + 1,
+        &message[tag_name_len..]
+    )
+}
+
+/// Wraps the message into the HTML `template`, replacing its placeholders with parts of the message:
+/// - `{raw}`: markup of the message without the trailing line break;
+/// - `{text}`: visible text of the message;
+/// - `{speaker}`: name of the sender, which the visible text of the message starts with;
+/// - `{time}`: timestamp of the message as HH:MM:SS;
+/// - `{class}`: classes of the message's opening tag, separated by spaces.
+///
+/// Placeholders are replaced by empty strings if the message has no such part, and all of them except for `{raw}` are
+/// escaped. Other text in braces is left as is. The line break after the message is kept after the filled template.
+pub fn apply_message_template(template: &str, message: &str) -> String {
+    let raw = message.trim_end();
+    let mut filled = String::with_capacity(template.len() + message.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find('}') else {
+            break;
+        };
+        let value = match &rest[1..end] {
+            "raw" => raw.to_string(),
+            "text" => parsing::escape_html(&parsing::plain_text(raw)),
+            "speaker" => parsing::message_sender(raw)
+                .map(|sender| parsing::escape_html(&sender))
+                .unwrap_or_default(),
+            "time" => timestamp::message_timestamp(raw)
+                .map(|timestamp| timestamp.to_string())
+                .unwrap_or_default(),
+            "class" => {
+                parsing::escape_html(&parsing::message_classes(raw).collect::<Vec<_>>().join(" "))
+            }
+            _ => {
+                // not a placeholder, the brace is kept
+                filled.push('{');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        filled.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    filled.push_str(rest);
+    filled.push_str(&message[raw.len()..]);
+    filled
+}
+
+/// Labels the table of contents link to the message with its timestamp and sender, e.g. "[12:05:30] John Doe". Falls
+/// back to the position of the message in the original log, if it has neither.
+fn toc_label(message: &str, index: usize) -> String {
+    let sender = parsing::message_sender(message).map(|sender| parsing::escape_html(&sender));
+    match (timestamp::message_timestamp(message), sender) {
+        (Some(timestamp), Some(sender)) => format!("[{}] {}", timestamp, sender),
+        (Some(timestamp), None) => format!("[{}]", timestamp),
+        (None, Some(sender)) => sender,
+        (None, None) => format!("Message {}", index + 1),
+    }
+}
+
+/// Chat logs merged into one by `merge_chat_logs`
+#[derive(Debug)]
+pub struct MergedLog {
+    pub chat_log: String,
+    /// Source of each message of the merged log, in order
+    pub sources: Vec<MessageSource>,
+    /// Messages left out for being identical to messages of earlier logs
+    pub duplicates: usize,
+}
+
+/// Origin of a message of merged logs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageSource {
+    /// Position of the log among the merged ones, starting from 0
+    pub log: usize,
+    /// Position of the message in its log, starting from 0
+    pub index: usize,
+}
+
+/// Merges chat logs into one with a single chat pane: the header of the first log with panes, messages of all logs in
+/// order and the footer of the last log. Markup between and around the panes of other logs is dropped. If no log has
+/// panes, the markup before the first message and after the last one is used instead.
+///
+/// If `dedup` is set, messages identical to messages of earlier logs, timestamps aside, are left out, so logs of the
+/// same round saved by several players can be merged without repeating it. Repeats within one log are kept.
+pub fn merge_chat_logs(chat_logs: &[String], dedup: bool) -> MergedLog {
+    let mut header = None;
+    let mut leading_markup = "";
+    let mut messages = Vec::new();
+    let mut sources = Vec::new();
+    let mut footer = "";
+    let mut earlier_messages = HashSet::new();
+    let mut duplicates = 0;
+    for (log_index, chat_log) in chat_logs.iter().enumerate() {
+        let LogParts {
+            header: log_header,
+            segments,
+            footer: log_footer,
+        } = split_chat_log(chat_log);
+        if log_index == 0
+            && let Some(Segment::Markup(markup)) = segments.first()
+        {
+            leading_markup = markup;
+        }
+        header = header.or(log_header);
+        let mut log_messages = HashSet::new();
+        for segment in &segments {
+            let Segment::Message(index, message) = *segment else {
+                continue;
+            };
+            if dedup {
+                // the last message of a pane may lack the line break the others end with
+                let key = parsing::without_timestamp(message).trim_end().to_string();
+                if earlier_messages.contains(&key) {
+                    duplicates += 1;
+                    continue;
+                }
+                log_messages.insert(key);
+            }
+            messages.push(message);
+            sources.push(MessageSource {
+                log: log_index,
+                index,
+            });
+        }
+        earlier_messages.extend(log_messages);
+        // footer of a log without panes doesn't close the pane, nor does the missing footer of a cut off log
+        footer = if header.is_some() && (log_header.is_none() || log_footer.is_empty()) {
+            CHAT_LOG_FOOTER
+        } else {
+            log_footer
+        };
+    }
+
+    let mut merged = String::new();
+    merged.push_str(header.unwrap_or(leading_markup));
+    messages.iter().for_each(|message| merged.push_str(message));
+    merged.push_str(footer);
+    MergedLog {
+        chat_log: merged,
+        sources,
+        duplicates,
+    }
+}
+
+/// Filters the merged logs like `filter_chat_log_with_options`. Returns the filtered log, its stats and the source of
+/// each kept message in order.
+pub fn filter_merged_chat_log(
+    merged: &MergedLog,
+    config: &Config,
+    options: FilterOptions<'_>,
+    timings: &mut StageTimings,
+) -> Result<(String, FilterStats, Vec<MessageSource>), anyhow::Error> {
+    let (filtered_chat_log, stats, kept_indices) =
+        filter_messages(&merged.chat_log, config, options, timings)?;
+    let kept_sources = kept_indices
+        .into_iter()
+        .map(|index| merged.sources[index])
+        .collect();
+    Ok((
+        filtered_chat_log,
+        FilterStats {
+            merge_duplicates: merged.duplicates,
+            ..stats
+        },
+        kept_sources,
+    ))
+}
+
+/// Splits the filtered chat log into parts, each no longer than `max_size` bytes if possible and carrying the header
+/// and the footer of the log, so that every part can be viewed on its own. Parts are split only between messages, so
+/// a part with a single long message may exceed `max_size`. Markup between panes stays in the part before it. Meant for
+/// filtered logs, which footer is always there.
+pub fn split_chat_log_by_size(chat_log: &str, max_size: usize) -> Vec<String> {
+    let LogParts {
+        header,
+        segments,
+        footer,
+    } = split_chat_log(chat_log);
+    // without panes, the markup before the first message serves as the header
+    let (header, segments) = match (header, segments.split_first()) {
+        (Some(header), _) => (header, &segments[..]),
+        (None, Some((Segment::Markup(leading_markup), rest))) => (*leading_markup, rest),
+        (None, _) => return vec![chat_log.to_string()],
+    };
+
+    let mut parts = Vec::new();
+    let mut part = header.to_string();
+    let mut has_messages = false;
+    for segment in segments {
+        let (html, is_message) = match segment {
+            Segment::Markup(markup) => (*markup, false),
+            Segment::Message(_, message) => (*message, true),
+        };
+        if is_message && has_messages && part.len() + html.len() + footer.len() > max_size {
+            part.push_str(footer);
+            parts.push(std::mem::replace(&mut part, header.to_string()));
+            has_messages = false;
+        }
+        part.push_str(html);
+        has_messages |= is_message;
+    }
+    part.push_str(footer);
+    parts.push(part);
+    parts
+}
+
+/// Finds the messages of the chat log, which `config` keeps, in order. Only `config.matches` is checked, without any of
+/// the filter options.
+pub fn matching_messages<'a>(
+    chat_log: &'a str,
+    config: &Config,
+) -> Result<Vec<&'a str>, anyhow::Error> {
+    let segments = split_chat_log(chat_log).segments;
+    let mut messages = Vec::new();
+    for segment in segments {
+        if let Segment::Message(_, message) = segment
+            && config.matches(message)?
+        {
+            messages.push(message);
+        }
+    }
+    Ok(messages)
+}
+
+/// Checks the structure of the chat log: that it has chat panes, that each message has as many closing div tags as
+/// opening ones and that the log isn't cut off before its footer. Returns the problems found, which are empty if the
+/// log is well-formed.
+pub fn validate_chat_log(chat_log: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    let LogParts {
+        header,
+        segments,
+        footer,
+    } = split_chat_log(chat_log);
+    if header.is_none() {
+        problems.push(format!(
+            "no {} was found, the whole document would be filtered",
+            CHAT_OPENING
+        ));
+    }
+    for segment in &segments {
+        if let Segment::Message(index, message) = segment {
+            let (opening, closing) = parsing::count_div_tags(message);
+            if opening != closing {
+                problems.push(format!(
+                    "message {} has {} opening and {} closing div tags",
+                    index + 1,
+                    opening,
+                    closing
+                ));
+            }
+        }
+    }
+    if header.is_some() && footer.is_empty() {
+        problems.push("the last chat pane isn't closed, the log may be cut off".to_string());
+    } else if !footer.to_ascii_lowercase().contains("</html") {
+        problems.push("the log ends before </html>, it may be cut off".to_string());
+    }
+    problems
+}
+
+/// Splits the tg chat log into its header, segments of its chat panes and its footer
+fn split_chat_log(chat_log: &str) -> LogParts<'_> {
+    parser::split_panes(chat_log, CHAT_OPENING, MESSAGE_MARKER)
+}
+
+/// Removes `<script>...</script>` blocks. Tags are found case-insensitively, an unclosed block is removed up to the
+/// end of `html`.
+pub fn strip_scripts(html: &str) -> Cow<'_, str> {
+    // ASCII lowercasing keeps byte offsets the same as in the original
+    let lowercase_html = html.to_ascii_lowercase();
+    if !lowercase_html.contains("<script") {
+        return Cow::Borrowed(html);
+    }
+
+    let mut stripped = String::with_capacity(html.len());
+    let mut position = 0;
+    while let Some(script_start) = lowercase_html[position..].find("<script") {
+        let script_start = position + script_start;
+        stripped.push_str(&html[position..script_start]);
+        position = lowercase_html[script_start..]
+            .find("</script>")
+            .map_or(html.len(), |script_end| {
+                script_start + script_end + "</script>".len()
+            });
+    }
+    stripped.push_str(&html[position..]);
+
+    Cow::Owned(stripped)
+}
+
+/// Marks the messages sent in the `phase` of the round: the first, middle or last third of the time between its first
+/// and last timestamps. Messages without a timestamp belong to the phase of the last timestamped message before them,
+/// or to the early phase if there is none, while a log without any timestamps has no phases at all. Timestamps going
+/// back by more than 12 hours are taken as the round going past midnight.
+fn messages_in_phase(messages: &[&str], phase: Phase) -> Vec<bool> {
+    const DAY: u32 = 24 * 60 * 60;
+    // seconds since the midnight before the first timestamp, which keep growing past the next midnight
+    let mut previous: Option<u32> = None;
+    let times: Vec<Option<u32>> = messages
+        .iter()
+        .map(|message| {
+            if let Some(timestamp) = timestamp::message_timestamp(message) {
+                let mut time = timestamp.seconds();
+                while previous.is_some_and(|previous| time + DAY / 2 < previous) {
+                    time += DAY;
+                }
+                previous = Some(time);
+            }
+            previous
+        })
+        .collect();
+    let (Some(first), Some(last)) = (
+        times.iter().flatten().min().copied(),
+        times.iter().flatten().max().copied(),
+    ) else {
+        return vec![false; messages.len()];
+    };
+
+    // the last timestamp is the end of the late phase, and a round of a single moment is all early
+    let span = u64::from(last - first).max(1);
+    let wanted = phase as u64;
+    times
+        .iter()
+        .map(|time| {
+            let elapsed = u64::from(time.unwrap_or(first) - first);
+            (elapsed * 3 / span).min(2) == wanted
+        })
+        .collect()
+}
+
+/// Marks the last `count` matching messages of every sender. Messages without a sender count as sent by one more
+/// sender.
+fn last_matches_per_speaker(messages: &[&str], matching: &[bool], count: usize) -> Vec<bool> {
+    let mut last_matches: HashMap<Option<String>, VecDeque<usize>> = HashMap::new();
+    for (index, message) in messages.iter().enumerate() {
+        if !matching[index] {
+            continue;
+        }
+        let indices = last_matches
+            .entry(parsing::message_sender(message))
+            .or_default();
+        indices.push_back(index);
+        if indices.len() > count {
+            indices.pop_front();
+        }
+    }
+
+    let mut kept = vec![false; messages.len()];
+    for index in last_matches.into_values().flatten() {
+        kept[index] = true;
+    }
+    kept
+}
+
+/// Marks every message, which is at most `context` messages away from a matching one
+fn with_context(matching: &[bool], context: usize) -> Vec<bool> {
+    let mut in_context = vec![false; matching.len()];
+    for (index, _) in matching.iter().enumerate().filter(|(_, matches)| **matches) {
+        let end = index
+            .saturating_add(context)
+            .saturating_add(1)
+            .min(matching.len());
+        in_context[index.saturating_sub(context)..end].fill(true);
+    }
+    in_context
+}
+
+/// Matches include patterns against the visible text of every run of `window` consecutive messages, joined with spaces
+/// so that a phrase split between messages is found regardless of the match field. Returns whether each message
+/// belongs to at least one matching window.
+fn match_windows(
+    messages: &[&str],
+    window: usize,
+    config: &Config,
+) -> Result<Vec<bool>, anyhow::Error> {
+    let mut in_matching_window = vec![false; messages.len()];
+    let window = window.min(messages.len()).max(1);
+    let texts: Vec<String> = messages.iter().map(|message| plain_text(message)).collect();
+
+    for (start, window_texts) in texts.windows(window).enumerate() {
+        let window_text = window_texts
+            .iter()
+            .filter(|text| !text.is_empty())
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(" ");
+        if config.matches_include_text(window_text)? {
+            in_matching_window[start..start + window].fill(true);
+        }
+    }
+
+    Ok(in_matching_window)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(include: &str) -> Config {
+        Config::from_args(ConfigArgs {
+            include: vec![include.to_string()],
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn markup_before_first_message_is_kept() {
+        let chat_log = concat!(
+            "<html><body><div class=\"Chat\">",
+            "<h1 class=\"round\">Round 42</h1>\n",
+            "<div class=\"ChatMessage\">the nuke is armed</div>\n",
+            "<div class=\"ChatMessage\">all clear</div>\n",
+            "</div>\n</body>\n</html>"
+        );
+        let filtered = filter_chat_log(chat_log, &config("nuke")).unwrap();
+        assert_eq!(
+            filtered,
+            concat!(
+                "<html><body><div class=\"Chat\">",
+                "<h1 class=\"round\">Round 42</h1>\n",
+                "<div class=\"ChatMessage\">the nuke is armed</div>\n",
+                "</div>\n</body>\n</html>"
+            )
+        );
+
+        // the markup is neither matched as a message, nor dropped when nothing is kept
+        let filtered = filter_chat_log(chat_log, &config("Round")).unwrap();
+        assert_eq!(
+            filtered,
+            concat!(
+                "<html><body><div class=\"Chat\">",
+                "<h1 class=\"round\">Round 42</h1>\n",
+                "</div>\n</body>\n</html>"
+            )
+        );
+    }
+
+    #[test]
+    fn strip_scripts_removes_script_blocks() {
+        assert_eq!(
+            strip_scripts("<b>hi</b><script>alert(1)</script><i>there</i>"),
+            "<b>hi</b><i>there</i>"
+        );
+        assert_eq!(
+            strip_scripts("a<SCRIPT type=\"text/javascript\">x()</Script>b<script>unclosed"),
+            "ab"
+        );
+        assert!(matches!(
+            strip_scripts("<b>no scripts</b>"),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn strip_scripts_option_sanitizes_header_and_messages() {
+        let chat_log = concat!(
+            "<html><head><script src=\"tracker.js\"></script></head><body><div class=\"Chat\">",
+            "<div class=\"ChatMessage\">nuke<script>document.cookie</script></div>\n",
+            "</div>\n</body>\n</html>"
+        );
+        let options = FilterOptions {
+            strip_scripts: true,
+            ..Default::default()
+        };
+        let (filtered, _) = filter_chat_log_with_options(
+            chat_log,
+            &config("nuke"),
+            options,
+            &mut StageTimings::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            filtered,
+            concat!(
+                "<html><head></head><body><div class=\"Chat\">",
+                "<div class=\"ChatMessage\">nuke</div>\n",
+                "</div>\n</body>\n</html>"
+            )
+        );
+    }
+
+    #[test]
+    fn strip_timestamps_removes_only_timestamps() {
+        let chat_log = concat!(
+            "<html><body><div class=\"Chat\">",
+            "<div class=\"ChatMessage\"><span class=\"timestamp\">[12:05:30]</span> <b>John</b> says, \"nuke\"</div>\n",
+            "<div class=\"ChatMessage\">untimed nuke</div>\n",
+            "</div>\n</body>\n</html>"
+        );
+        let options = FilterOptions {
+            strip_timestamps: true,
+            ..Default::default()
+        };
+        let (output, stats) = filter_chat_log_with_options(
+            chat_log,
+            &config("nuke"),
+            options,
+            &mut StageTimings::default(),
+        )
+        .unwrap();
+        assert_eq!(stats.kept, 2);
+        assert_eq!(
+            output,
+            chat_log.replace("<span class=\"timestamp\">[12:05:30]</span>", "")
+        );
+    }
+
+    #[test]
+    fn toc_links_are_labeled_with_timestamps_and_senders() {
+        let chat_log = concat!(
+            "<html><body><div class=\"Chat\">",
+            "<div class=\"ChatMessage\"><span class=\"timestamp\">[12:05:30]</span> <b>John &lt;3</b> says, \"nuke\"</div>",
+            "<div class=\"ChatMessage\"><span class=\"timestamp\">[12:06]</span> The nuke is armed.</div>",
+            "<div class=\"ChatMessage\"><b>Jane</b> says, \"nuke\"</div>",
+            "<div class=\"ChatMessage\">nuke</div>",
+            "</div>\n</body>\n</html>"
+        );
+        let options = FilterOptions {
+            toc: true,
+            ..Default::default()
+        };
+        let (filtered, _) = filter_chat_log_with_options(
+            chat_log,
+            &config("nuke"),
+            options,
+            &mut StageTimings::default(),
+        )
+        .unwrap();
+        assert!(filtered.contains(concat!(
+            "<div class=\"ChatToc\"><ol>",
+            "<li><a href=\"#message-1\">[12:05:30] John &lt;3</a></li>",
+            "<li><a href=\"#message-2\">[12:06:00]</a></li>",
+            "<li><a href=\"#message-3\">Jane</a></li>",
+            "<li><a href=\"#message-4\">Message 4</a></li>",
+            "</ol></div>"
+        )));
+        assert!(filtered.contains("<div id=\"message-4\" class=\"ChatMessage\">nuke</div>"));
+    }
+
+    #[test]
+    fn window_matches_phrase_split_between_messages() {
+        let chat_log = concat!(
+            "<html><body><div class=\"Chat\">",
+            "<div class=\"ChatMessage\"><b>John</b> says, \"the nuke</div>\n",
+            "<div class=\"ChatMessage\"><i>is armed</i>\"</div>\n",
+            "<div class=\"ChatMessage\">all clear</div>\n",
+            "</div>\n</body>\n</html>"
+        );
+        for match_field in [MatchField::Full, MatchField::Text] {
+            let config = Config::from_args(ConfigArgs {
+                include: vec!["Nuke is armed".to_string()],
+                match_field: Some(match_field),
+                ..Default::default()
+            })
+            .unwrap();
+            let options = FilterOptions {
+                window: Some(2),
+                ..Default::default()
+            };
+            let (_, stats) = filter_chat_log_with_options(
+                chat_log,
+                &config,
+                options,
+                &mut StageTimings::default(),
+            )
+            .unwrap();
+            assert_eq!(stats.kept, 2, "{:?}", match_field);
+        }
+    }
+
+    #[test]
+    fn pipeline_stages_apply_in_order() {
+        let chat_log = concat!(
+            "<html><body><div class=\"Chat\">",
+            "<div class=\"ChatMessage\"><span class=\"timestamp\">[12:00:00]</span>nuke 1</div>\n",
+            "<div class=\"ChatMessage\"><span class=\"timestamp\">[12:00:01]</span>nuke 1</div>\n",
+            "<div class=\"ChatMessage\">nuke 2</div>\n",
+            "<div class=\"ChatMessage\">nuke 2</div>\n",
+            "<div class=\"ChatMessage\">nuke 2</div>\n",
+            "<div class=\"ChatMessage\">calm</div>\n",
+            "<div class=\"ChatMessage\">nuke 1</div>\n",
+            "</div>\n</body>\n</html>"
+        );
+        let filtered = |options: FilterOptions<'_>| {
+            let options = FilterOptions {
+                format: OutputFormat::Plain,
+                ..options
+            };
+            filter_chat_log_with_options(
+                chat_log,
+                &config("nuke"),
+                options,
+                &mut StageTimings::default(),
+            )
+            .unwrap()
+        };
+
+        let (output, stats) = filtered(FilterOptions {
+            dedup: true,
+            ..Default::default()
+        });
+        assert_eq!(output, "[12:00:00]nuke 1\nnuke 2\nnuke 1\n");
+        assert_eq!((stats.kept, stats.deduplicated), (3, 3));
+
+        // the limit counts deduplicated messages, and repeats of the last kept one are still collapsed
+        let (output, stats) = filtered(FilterOptions {
+            dedup: true,
+            mark_repeats: true,
+            limit: Some(2),
+            ..Default::default()
+        });
+        assert_eq!(output, "[12:00:00]nuke 1 (x 2)\nnuke 2 (x 3)\n");
+        assert_eq!((stats.kept, stats.deduplicated), (2, 3));
+        assert!(stats.limit_reached);
+
+        let (output, stats) = filtered(FilterOptions {
+            limit: Some(2),
+            ..Default::default()
+        });
+        assert_eq!(output, "[12:00:00]nuke 1\n[12:00:01]nuke 1\n");
+        assert!(stats.limit_reached);
+
+        let (_, stats) = filtered(FilterOptions {
+            dedup: true,
+            limit: Some(3),
+            ..Default::default()
+        });
+        assert!(!stats.limit_reached);
+
+        // repeats of a message omitted to fit the budget are collapsed rather than omitted
+        let (output, stats) = filtered(FilterOptions {
+            dedup: true,
+            output_budget: Some("[12:00:00]nuke 1\n".len()),
+            ..Default::default()
+        });
+        assert_eq!(output, "[12:00:00]nuke 1\n");
+        assert_eq!((stats.omitted_by_budget, stats.deduplicated), (2, 3));
+
+        // context is part of filtering, so it is added before dedup
+        let (output, _) = filtered(FilterOptions {
+            dedup: true,
+            context: Some(1),
+            ..Default::default()
+        });
+        assert_eq!(output, "[12:00:00]nuke 1\nnuke 2\ncalm\nnuke 1\n");
+    }
+
+    #[test]
+    fn last_matches_of_each_speaker_are_kept_in_order() {
+        let chat_log = concat!(
+            "<html><body><div class=\"Chat\">",
+            "<div class=\"ChatMessage\"><b>John</b> nuke 1</div>\n",
+            "<div class=\"ChatMessage\"><b>Jane</b> nuke A</div>\n",
+            "<div class=\"ChatMessage\"><b>John</b> nuke 2</div>\n",
+            "<div class=\"ChatMessage\">nuke alarm</div>\n",
+            "<div class=\"ChatMessage\"><b>John</b> nuke 3</div>\n",
+            "<div class=\"ChatMessage\"><b>Jane</b> calm</div>\n",
+            "<div class=\"ChatMessage\"><b>Jane</b> nuke B</div>\n",
+            "</div>\n</body>\n</html>"
+        );
+        let options = FilterOptions {
+            last_per_speaker: Some(2),
+            format: OutputFormat::Plain,
+            ..Default::default()
+        };
+        let (output, stats) = filter_chat_log_with_options(
+            chat_log,
+            &config("nuke"),
+            options,
+            &mut StageTimings::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            output,
+            "Jane nuke A\nJohn nuke 2\nnuke alarm\nJohn nuke 3\nJane nuke B\n"
+        );
+        assert_eq!(stats.kept, 5);
+    }
+
+    #[test]
+    fn phases_split_the_round_into_thirds() {
+        let message = |time: &str, text: &str| {
+            format!(
+                "<div class=\"ChatMessage\"><span class=\"timestamp\">[{}]</span> nuke {}</div>\n",
+                time, text
+            )
+        };
+        // the round goes past midnight, from 23:00 to 02:00
+        let chat_log = [
+            "<html><body><div class=\"Chat\">".to_string(),
+            "<div class=\"ChatMessage\">nuke before</div>\n".to_string(),
+            message("23:00", "start"),
+            message("23:59", "early"),
+            message("00:01", "mid"),
+            "<div class=\"ChatMessage\">nuke untimed</div>\n".to_string(),
+            message("00:30", "mid"),
+            message("01:00", "late"),
+            message("02:00", "end"),
+            "</div>\n</body>\n</html>".to_string(),
+        ]
+        .concat();
+        let phase_texts = |phase: Phase| {
+            let options = FilterOptions {
+                phase: Some(phase),
+                strip_timestamps: true,
+                format: OutputFormat::Plain,
+                ..Default::default()
+            };
+            filter_chat_log_with_options(
+                &chat_log,
+                &config("nuke"),
+                options,
+                &mut StageTimings::default(),
+            )
+            .unwrap()
+            .0
+        };
+        assert_eq!(
+            phase_texts(Phase::Early),
+            "nuke before\nnuke start\nnuke early\n"
+        );
+        assert_eq!(
+            phase_texts(Phase::Mid),
+            "nuke mid\nnuke untimed\nnuke mid\n"
+        );
+        assert_eq!(phase_texts(Phase::Late), "nuke late\nnuke end\n");
+    }
+
+    #[test]
+    fn formats_are_rendered_from_the_same_matches() {
+        let chat_log = concat!(
+            "<html><body><div class=\"Chat\">",
+            "<div class=\"ChatMessage\">nuke 1</div>\n",
+            "<div class=\"ChatMessage\">calm</div>\n",
+            "<div class=\"ChatMessage\">nuke 2</div>\n",
+            "</div>\n</body>\n</html>"
+        );
+        let options = FilterOptions {
+            toc: true,
+            separator: Some("<hr>"),
+            ..Default::default()
+        };
+        let formats = [OutputFormat::Html, OutputFormat::Plain, OutputFormat::Jsonl];
+        let filtered = filter_chat_log_in_formats(
+            chat_log,
+            &config("nuke"),
+            options,
+            &formats,
+            &mut StageTimings::default(),
+        )
+        .unwrap();
+
+        for (format, (output, stats)) in formats.iter().zip(&filtered) {
+            let (single, single_stats) = filter_chat_log_with_options(
+                chat_log,
+                &config("nuke"),
+                FilterOptions {
+                    format: *format,
+                    toc: *format == OutputFormat::Html,
+                    separator: options.separator.filter(|_| *format == OutputFormat::Html),
+                    ..options
+                },
+                &mut StageTimings::default(),
+            )
+            .unwrap();
+            assert_eq!(*output, single, "{:?}", format);
+            assert_eq!(stats.kept, single_stats.kept);
+        }
+        assert!(filtered[0].0.contains("<hr>") && filtered[0].0.contains("ChatToc"));
+        // table of contents and separators are only added to HTML
+        assert_eq!(filtered[1].0, "nuke 1\nnuke 2\n");
+    }
+
+    #[test]
+    fn message_template_wraps_kept_html_messages() {
+        let chat_log = concat!(
+            "<html><body><div class=\"Chat\">",
+            "<div class=\"ChatMessage say\"><span class=\"timestamp\">[12:05]</span> <b>John &lt;3</b> says, ",
+            "\"nuke\"</div>\n",
+            "<div class=\"ChatMessage\">nuke alarm</div>\n",
+            "</div>\n</body>\n</html>"
+        );
+        let template = "<article class=\"{class}\" title=\"{time} {speaker}\">{raw}<p>{text}</p>{unknown}</article>";
+        let filtered = |format: OutputFormat| {
+            filter_chat_log_with_options(
+                chat_log,
+                &config("nuke"),
+                FilterOptions {
+                    message_template: Some(template),
+                    format,
+                    ..Default::default()
+                },
+                &mut StageTimings::default(),
+            )
+            .unwrap()
+            .0
+        };
+        assert_eq!(
+            filtered(OutputFormat::Html),
+            concat!(
+                "<html><body><div class=\"Chat\">",
+                "<article class=\"ChatMessage say\" title=\"12:05:00 John &lt;3\"><div class=\"ChatMessage say\">",
+                "<span class=\"timestamp\">[12:05]</span> <b>John &lt;3</b> says, \"nuke\"</div>",
+                "<p>[12:05] John &lt;3 says, &quot;nuke&quot;</p>{unknown}</article>\n",
+                "<article class=\"ChatMessage\" title=\" \"><div class=\"ChatMessage\">nuke alarm</div>",
+                "<p>nuke alarm</p>{unknown}</article>\n",
+                "</div>\n</body>\n</html>"
+            )
+        );
+        // other formats are written without the template
+        assert_eq!(
+            filtered(OutputFormat::Plain),
+            "[12:05] John <3 says, \"nuke\"\nnuke alarm\n"
+        );
+    }
+
+    #[test]
+    fn jsonl_has_one_object_per_kept_message() {
+        let chat_log = concat!(
+            "<html><body><div class=\"Chat\">",
+            "<div class=\"ChatMessage say\">\"nuke\"</div>\n",
+            "<div class=\"ChatMessage say\">\"nuke\"</div>\n",
+            "<div class=\"ChatMessage\">calm</div>\n",
+            "<div class=\"ChatMessage ooc\">nuke\nagain</div>\n",
+            "</div>\n</body>\n</html>"
+        );
+        let options = FilterOptions {
+            format: OutputFormat::Jsonl,
+            dedup: true,
+            mark_repeats: true,
+            ..Default::default()
+        };
+        let (output, stats) = filter_chat_log_with_options(
+            chat_log,
+            &config("nuke"),
+            options,
+            &mut StageTimings::default(),
+        )
+        .unwrap();
+        assert_eq!(stats.lines_out, 2);
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines[0]["text"], "\"nuke\" (x 2)");
+        assert_eq!(
+            lines[0]["classes"],
+            serde_json::json!(["ChatMessage", "say"])
+        );
+        assert_eq!(
+            lines[1]["html"],
+            "<div class=\"ChatMessage ooc\">nuke\nagain</div>\n"
+        );
+    }
+
+    #[test]
+    fn merge_drops_messages_of_earlier_logs() {
+        let log = |messages: &[&str]| {
+            let messages: String = messages
+                .iter()
+                .map(|message| format!("<div class=\"ChatMessage\">{}</div>\n", message))
+                .collect();
+            format!(
+                "<html><body><div class=\"Chat\">{}</div>\n</body>\n</html>",
+                messages
+            )
+        };
+        let first = log(&["<span class=\"timestamp\">[12:00]</span>hi", "hi", "bye"]);
+        let second = log(&["<span class=\"timestamp\">[12:01]</span>hi", "nuke"]);
+        let chat_logs = [first, second];
+
+        let merged = merge_chat_logs(&chat_logs, true);
+        assert_eq!(merged.duplicates, 1);
+        // repeats within the first log are kept
+        assert_eq!(merged.chat_log.matches(">hi<").count(), 2);
+        assert!(merged.chat_log.contains("nuke"));
+
+        let merged = merge_chat_logs(&chat_logs, false);
+        assert_eq!(merged.duplicates, 0);
+        assert_eq!(merged.chat_log.matches(">hi<").count(), 3);
+    }
+
+    #[test]
+    fn kept_messages_of_merged_logs_are_traced_to_their_sources() {
+        let chat_logs = [
+            "<html><body><div class=\"Chat\"><div class=\"ChatMessage\">nuke</div>\n<div class=\"ChatMessage\">hi</div>\n</div>\n</body>\n</html>".to_string(),
+            "<html><body><div class=\"Chat\"><div class=\"ChatMessage\">hi</div>\n<div class=\"ChatMessage\">nuke armed</div>\n</div>\n</body>\n</html>".to_string(),
+        ];
+        let merged = merge_chat_logs(&chat_logs, false);
+        let (_, stats, sources) = filter_merged_chat_log(
+            &merged,
+            &config("nuke"),
+            FilterOptions::default(),
+            &mut StageTimings::default(),
+        )
+        .unwrap();
+        assert_eq!(stats.kept, 2);
+        assert_eq!(
+            sources,
+            [
+                MessageSource { log: 0, index: 0 },
+                MessageSource { log: 1, index: 1 }
+            ]
+        );
+    }
+}
@@ -1,92 +1,165 @@
 use std::{
-    fs::{OpenOptions, create_dir_all, read_to_string},
-    io::{Read, Write, stdin},
+    borrow::Cow,
+    collections::{BTreeSet, HashMap, HashSet},
+    fs::{File, OpenOptions, create_dir_all, read_to_string, remove_file, rename},
+    io::{self, ErrorKind, IsTerminal, Read, Seek, SeekFrom, Write, stdin},
+    iter,
+    ops::Range,
     path::{Path, PathBuf},
-    process::exit,
-    time::Instant,
+    process::{self, exit},
+    slice,
+    sync::mpsc,
+    thread::sleep,
+    time::{Duration, Instant},
 };
 
 use clap::Parser;
+use flate2::{Compression, write::GzEncoder};
+use glob::Pattern;
+use indicatif::{ProgressBar, ProgressStyle};
+use notify::{EventKind, RecursiveMode, Watcher, event::ModifyKind};
+use owo_colors::OwoColorize;
+use rayon::{ThreadPoolBuilder, prelude::*};
+use regex::Regex;
+use serde::Serialize;
 
-use crate::config::Config;
-
-mod config;
-
-/// Simple CLI utility to filter the Space Station 13 saved chat logs
-#[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-struct Cli {
-    /// Paths to chat log files to filter
-    #[arg(short, long, value_name = "FILES")]
-    paths: Vec<PathBuf>,
-
-    /// Paths to the output files. Defaults to "{out_dir}/filtered_{INPUT FILE NAME}". out_dir defaults to current working
-    /// directory the program's working directory. Missing directories in the path will be created recursively. If more
-    /// paths than outputs were provided, missing outputs will be set to default. If more outputs than paths
-    /// were provided, excessive outputs will be ignored.
-    #[arg(short, long, value_name = "FILES")]
-    outputs: Vec<PathBuf>,
+use ss13_mlogfilter::{
+    Anonymizer, BuiltinParser, CHAT_OPENING, Config, DEFAULT_CONFIG, FilterOptions, FilterStats,
+    MessageParser, OutputFormat, StageTimings,
+    cli::{Cli, CollisionPolicy},
+    filter_chat_log_in_formats, filter_merged_chat_log,
+    input::{ReadOptions, open_chat_log, read_chat_log},
+    matching_messages, merge_chat_logs, plain_text, split_chat_log_by_size, stream,
+    validate_chat_log,
+};
 
-    /// Path to the directory, which will be considered base for default outputs. Missing directories in the path will be
-    /// created recursively.
-    #[arg(long, value_name = "DIR")]
-    out_dir: Option<PathBuf>,
+use crate::archive::{TarOutput, entry_name};
 
-    /// Read paths from standard input, separated by whitespaces
-    #[arg(long)]
-    stdin: bool,
+mod archive;
+#[cfg(test)]
+#[path = "test_files.rs"]
+mod test_files;
 
-    /// Exits the program if failed to filter one or more paths
-    #[arg(long)]
-    strict: bool,
+/// Time without changes to the watched logs, after which they are filtered again
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
 
-    /// Allow overwrite of the output file
-    #[arg(long)]
-    overwrite: bool,
+/// Time between checks of the followed logs for new contents
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
 
-    /// Match case
-    #[arg(long)]
-    match_case: bool,
+/// Prints a progress line to the standard output, or to the standard error if `to_stderr` is set
+macro_rules! progress {
+    ($to_stderr:expr, $($arg:tt)*) => {
+        if $to_stderr {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
 
-    /// Treat include & exclude patterns as regexes
-    #[arg(long)]
-    regex: bool,
+/// Options of processing a single log: filtering it along with reading the input and writing the output
+#[derive(Debug, Default, Clone, Copy)]
+struct ProcessOptions<'a> {
+    filter: FilterOptions<'a>,
+    /// How the input is read and decoded
+    read: ReadOptions,
+    /// Unix permissions of the written output file
+    output_mode: Option<u32>,
+    /// Whether to write the filtered log to the standard output instead of the output file
+    to_stdout: bool,
+    /// Whether to gzip the written output
+    gzip_output: bool,
+    /// Whether to only filter the log without writing the output
+    count_only: bool,
+    /// Maximum size of each part the output is split into
+    split_size: Option<usize>,
+    /// Formats the log is filtered into, each written next to the output named by its extension if there are several.
+    /// Only the format of `filter` if empty.
+    formats: &'a [OutputFormat],
+}
 
-    /// Patterns that has to be included in the output
-    #[arg(short, long)]
-    include: Option<String>,
+impl ProcessOptions<'_> {
+    /// Lowers the limit of kept messages to `limit`, if it is stricter
+    fn with_limit(mut self, limit: Option<usize>) -> Self {
+        self.filter.limit = self.filter.limit.into_iter().chain(limit).min();
+        self
+    }
 
-    /// Patterns that has to be excluded from the output
-    #[arg(short, long)]
-    exclude: Option<String>,
+    /// Formats the log is filtered into
+    fn formats(&self) -> &[OutputFormat] {
+        if self.formats.is_empty() {
+            slice::from_ref(&self.filter.format)
+        } else {
+            self.formats
+        }
+    }
+}
 
-    /// Path to a config file
-    #[arg(short, long, value_name = "FILE")]
-    config: Option<PathBuf>,
+/// Result of processing a single log, reported after all logs are processed
+struct ProcessedLog {
+    result: Result<Option<FilterStats>, anyhow::Error>,
+    timings: StageTimings,
+    /// Total time spent on the log
+    elapsed: Duration,
 }
 
 fn main() {
     let start = Instant::now();
 
     let mut cli = Cli::parse();
+    cli.count |= cli.count_messages || cli.count_lines;
+
+    if let Some(config_path) = &cli.init_config {
+        create_output_file(config_path, cli.may_overwrite(config_path))
+            .and_then(|mut config_file| {
+                config_file
+                    .write_all(DEFAULT_CONFIG.as_bytes())
+                    .map_err(anyhow::Error::from)
+            })
+            .unwrap_or_else(|err| {
+                eprintln!(
+                    "Failed to write the config to {}: {}",
+                    config_path.to_string_lossy(),
+                    err
+                );
+                exit(1);
+            });
+        println!(
+            "Wrote the default config to {}",
+            config_path.to_string_lossy()
+        );
+        exit(0);
+    }
 
-    let config: Config;
+    let config = Config::try_from(&cli).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        exit(1);
+    });
 
-    if let Some(config_path) = cli.config {
-        config = Config::load(&config_path).unwrap_or_else(|err| {
-            eprintln!(
-                "Failed to load config from {}: {}",
-                config_path.to_string_lossy(),
-                err
-            );
+    if cli.check_config {
+        if let Err(err) = config.validate() {
+            eprintln!("Invalid config: {}", err);
+            exit(1);
+        }
+        println!("Config is valid");
+        exit(0);
+    }
+
+    if cli.print_config {
+        let config_string = match cli.format[0] {
+            OutputFormat::Json | OutputFormat::Jsonl => serde_json::to_string_pretty(&config)
+                .map(|config_string| config_string + "\n")
+                .map_err(anyhow::Error::from),
+            OutputFormat::Html | OutputFormat::Plain => {
+                toml::to_string(&config).map_err(anyhow::Error::from)
+            }
+        }
+        .unwrap_or_else(|err| {
+            eprintln!("Failed to serialize the config: {}", err);
             exit(1);
         });
-    } else {
-        config = Config::from_args(cli.regex, cli.include, cli.exclude, cli.match_case)
-            .unwrap_or_else(|err| {
-                eprintln!("Failed to parse arguments: {}", err);
-                exit(1)
-            });
+        print!("{}", config_string);
+        exit(0);
     }
 
     if cli.stdin {
@@ -99,85 +172,1525 @@ fn main() {
             .split_whitespace()
             .map(|path| path.into())
             .collect();
-        println!(
+        progress!(
+            cli.stdout,
             "Parsed {} paths from the standard input.",
             stdin_paths.len()
         );
         cli.paths.append(&mut stdin_paths);
     }
 
+    let exit_if_strict = |err: anyhow::Error| {
+        eprintln!("{}", err);
+        if cli.strict {
+            eprintln!("Encountered error in strict mode. Exiting...");
+            exit(1)
+        }
+    };
+    // directories in the inputs are replaced by the logs found in them, along with their subdirectories
+    let (paths, subdirs): (Vec<PathBuf>, Vec<PathBuf>) = cli
+        .paths
+        .iter()
+        .flat_map(|path| {
+            expand_glob(path).unwrap_or_else(|err| {
+                exit_if_strict(err);
+                Vec::new()
+            })
+        })
+        .flat_map(|path| {
+            if path.is_dir() {
+                discover_logs(&path, &cli.file_glob, exit_if_strict)
+            } else {
+                vec![(path, PathBuf::new())]
+            }
+        })
+        .unzip();
+    cli.paths = paths;
+
     if cli.paths.is_empty() {
         eprintln!("No valid paths were provided");
         exit(1)
     }
 
-    for (index, log_path) in cli.paths.iter().enumerate() {
+    let mut output_paths: Vec<PathBuf> = cli
+        .paths
+        .iter()
+        .enumerate()
+        .map(|(index, log_path)| {
+            get_path_for_output(
+                index,
+                &cli.outputs,
+                log_path,
+                &subdirs[index],
+                &cli.out_dir,
+                cli.gzip_output,
+            )
+        })
+        .collect();
+    let several_formats = cli.format.len() > 1;
+    if several_formats {
+        if cli.stdout || cli.merge.is_some() || cli.follow {
+            eprintln!(
+                "Several formats can't be written to the standard output, merged or followed"
+            );
+            exit(1);
+        }
+        output_paths = output_paths
+            .iter()
+            .map(|output_path| with_format_extension(output_path, cli.format[0]))
+            .collect();
+    }
+    let other_parser = cli.parser != BuiltinParser::Tg;
+    if other_parser
+        && (cli.merge.is_some()
+            || cli.follow
+            || cli.preview
+            || cli.validate
+            || cli.split_size.is_some())
+    {
+        eprintln!(
+            "Only logs of the tg parser can be merged, followed, previewed, validated or split into parts"
+        );
+        exit(1);
+    }
+    let collision_result =
+        resolve_output_collisions(&cli.paths, &mut output_paths, cli.on_collision);
+
+    let anonymizer = cli
+        .anonymize_speakers
+        .then(|| Anonymizer::new(cli.anon_seed));
+    let message_template = cli.message_template.as_ref().map(|template_path| {
+        // the line break ending the file isn't part of the snippet, messages keep their own
+        read_to_string(template_path)
+            .map(|template| template.trim_end_matches(['\r', '\n']).to_string())
+            .unwrap_or_else(|err| {
+                eprintln!(
+                    "Failed to read the message template from {}: {}",
+                    template_path.to_string_lossy(),
+                    err
+                );
+                exit(1);
+            })
+    });
+    // the table of contents, separators and parts are HTML
+    let is_html = cli.format.contains(&OutputFormat::Html);
+    let filter_options = FilterOptions {
+        limit: cli.limit,
+        window: cli.window_match.then_some(cli.window_size.into()),
+        context: cli.context,
+        last_per_speaker: cli.last_per_speaker,
+        phase: cli.phase,
+        output_budget: cli.output_budget,
+        separator: cli.separator.as_deref().filter(|_| is_html),
+        message_template: message_template.as_deref().filter(|_| is_html),
+        strip_scripts: cli.strip_scripts,
+        strip_timestamps: cli.strip_timestamps,
+        anonymizer: anonymizer.as_ref(),
+        max_message_length: cli.max_message_length,
+        strict: cli.strict,
+        toc: cli.toc && is_html,
+        format: cli.format[0],
+        pretty_json: cli.pretty,
+        dedup: cli.dedup,
+        mark_repeats: cli.dedup_count,
+        parser: other_parser.then_some(&cli.parser as &dyn MessageParser),
+    };
+    let options = ProcessOptions {
+        filter: filter_options,
+        read: ReadOptions {
+            retries: cli.retry_read,
+            detect_encoding: cli.encoding_detect,
+            strict: cli.strict,
+        },
+        output_mode: cli.chmod,
+        to_stdout: cli.stdout,
+        gzip_output: cli.gzip_output,
+        count_only: cli.count,
+        split_size: cli.split_size.filter(|_| is_html),
+        formats: &cli.format,
+    };
+
+    if cli.preflight {
+        let passed = run_preflight(&cli, &config, &output_paths, collision_result.err());
+        exit(if passed { 0 } else { 1 });
+    }
+
+    if cli.validate {
+        let passed = run_validation(&cli, options);
+        exit(if passed || !cli.strict { 0 } else { 1 });
+    }
+
+    if cli.preview {
+        run_preview(&cli, &config, options).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            exit(1);
+        });
+        exit(0);
+    }
+
+    // outputs aren't written when printing to the standard output, counting or merging, so they may collide
+    if !cli.stdout && !cli.count && cli.merge.is_none() {
+        collision_result.unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            exit(1);
+        });
+    }
+
+    if cli.follow {
+        if !ss13_mlogfilter::can_stream(&config, options.filter) {
+            eprintln!("--follow can't be used with options, which need the whole log");
+            exit(1);
+        }
+        follow_paths(&cli, &config, options.filter, &output_paths);
+        return;
+    }
+
+    let mut tar = cli.tar.as_ref().map(|tar_path| {
+        TarOutput::create(tar_path, cli.may_overwrite(tar_path)).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            exit(1);
+        })
+    });
+
+    if let Some(merge_path) = &cli.merge {
+        let merge_start = Instant::now();
+        let mut timings = StageTimings::default();
+        let result = merge_paths(
+            &cli.paths,
+            merge_path,
+            &config,
+            options.with_limit(cli.limit_total),
+            cli.may_overwrite(merge_path),
+            exit_if_strict,
+            &mut timings,
+        )
+        .and_then(|(stats, kept_sources)| {
+            if let Some(index_path) = &cli.merge_index {
+                write_merge_index(index_path, &kept_sources, cli.may_overwrite(index_path))?;
+            }
+            Ok(stats)
+        });
+        let processed = ProcessedLog {
+            result: result.map(Some),
+            timings,
+            elapsed: merge_start.elapsed(),
+        };
+        let log_name = format!("{} merged logs", cli.paths.len());
+        report_processed(&cli, &log_name, merge_path, processed);
+        progress!(
+            cli.stdout,
+            "Merged {} logs in {}ms",
+            cli.paths.len(),
+            start.elapsed().as_millis()
+        );
+        if cli.profile {
+            progress!(cli.stdout, "Total profile: {}", timings);
+        }
+        write_anon_map_if_requested(&cli, anonymizer.as_ref());
+        return;
+    }
+
+    let progress_bar = if cli.quiet || !io::stdout().is_terminal() || !io::stderr().is_terminal() {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(cli.paths.len() as u64).with_style(
+            ProgressStyle::with_template("{bar:40} {pos}/{len} logs, {elapsed} elapsed, ETA {eta}")
+                .unwrap_or_else(|_| ProgressStyle::default_bar()),
+        )
+    };
+
+    let mut remaining_total = cli.limit_total;
+    let mut skipped_by_limit: Vec<&PathBuf> = Vec::new();
+    let mut total_timings = StageTimings::default();
+
+    let process = |log_path: &PathBuf,
+                   output_path: &PathBuf,
+                   limit: Option<usize>,
+                   tar: Option<&mut TarOutput>| {
         let this_path_start = Instant::now();
-        let output_path = get_path_for_output(index, &cli.outputs, log_path, &cli.out_dir);
+        let mut timings = StageTimings::default();
+        let result = process_path(
+            log_path,
+            output_path,
+            &config,
+            options.with_limit(limit),
+            cli.may_overwrite(output_path),
+            tar,
+            &mut timings,
+        );
+        progress_bar.inc(1);
+        ProcessedLog {
+            result,
+            timings,
+            elapsed: this_path_start.elapsed(),
+        }
+    };
+
+    // archive entries, standard output and the total limit depend on the order of inputs
+    if tar.is_some() || cli.stdout || cli.limit_total.is_some() {
+        for (log_path, output_path) in cli.paths.iter().zip(&output_paths) {
+            if remaining_total == Some(0) {
+                skipped_by_limit.push(log_path);
+                progress_bar.inc(1);
+                continue;
+            }
+
+            let processed = process(log_path, output_path, remaining_total, tar.as_mut());
+            if let Ok(Some(stats)) = &processed.result {
+                remaining_total =
+                    remaining_total.map(|remaining| remaining.saturating_sub(stats.kept));
+            }
+            total_timings += processed.timings;
+            progress_bar.suspend(|| {
+                report_processed(&cli, &log_path.to_string_lossy(), output_path, processed)
+            });
+        }
+    } else {
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(cli.jobs.map(usize::from).unwrap_or_default())
+            .build()
+            .unwrap_or_else(|err| {
+                eprintln!("Failed to start worker threads: {}", err);
+                exit(1);
+            });
+        let processed: Vec<ProcessedLog> = pool.install(|| {
+            cli.paths
+                .par_iter()
+                .zip(&output_paths)
+                .map(|(log_path, output_path)| process(log_path, output_path, None, None))
+                .collect()
+        });
+
+        for ((log_path, output_path), processed) in
+            cli.paths.iter().zip(&output_paths).zip(processed)
+        {
+            total_timings += processed.timings;
+            progress_bar.suspend(|| {
+                report_processed(&cli, &log_path.to_string_lossy(), output_path, processed)
+            });
+        }
+    }
+
+    progress_bar.finish_and_clear();
+
+    let filtered_count = cli.paths.len() - skipped_by_limit.len();
+    if !skipped_by_limit.is_empty() {
+        progress!(
+            cli.stdout,
+            "Reached the total limit of {} messages, skipped {} logs:",
+            cli.limit_total.unwrap_or_default(),
+            skipped_by_limit.len()
+        );
+        for log_path in skipped_by_limit {
+            progress!(cli.stdout, "  {}", log_path.to_string_lossy());
+        }
+    }
+
+    if let Some(tar) = tar {
+        tar.finish().unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            exit(1);
+        });
+    }
+    write_anon_map_if_requested(&cli, anonymizer.as_ref());
+
+    progress!(
+        cli.stdout,
+        "Filtered {} logs in {}ms",
+        filtered_count,
+        start.elapsed().as_millis()
+    );
+    if cli.profile {
+        progress!(cli.stdout, "Total profile: {}", total_timings);
+    }
+
+    if cli.watch {
+        progress!(
+            cli.stdout,
+            "Watching {} logs for changes, press Ctrl-C to stop",
+            cli.paths.len()
+        );
+        let refilter = |index: usize| {
+            let (log_path, output_path) = (&cli.paths[index], &output_paths[index]);
+            let refilter_start = Instant::now();
+            let mut timings = StageTimings::default();
+            let result = process_path(
+                log_path,
+                output_path,
+                &config,
+                options.with_limit(cli.limit_total),
+                cli.may_overwrite(output_path),
+                None,
+                &mut timings,
+            );
+            let processed = ProcessedLog {
+                result,
+                timings,
+                elapsed: refilter_start.elapsed(),
+            };
+            report_processed(&cli, &log_path.to_string_lossy(), output_path, processed);
+        };
+        watch_paths(&cli.paths, refilter).unwrap_or_else(|err| {
+            eprintln!("Failed to watch the logs: {}", err);
+            exit(1);
+        });
+    }
+}
 
-        match process_path(log_path, &output_path, &config, cli.overwrite) {
-            Ok(()) => {
+/// Watches the logs at `paths` and calls `refilter` with the index of each changed log. Changes are debounced, so a log
+/// written in several chunks is filtered once. Returns only if watching fails.
+fn watch_paths(paths: &[PathBuf], refilter: impl Fn(usize)) -> Result<(), anyhow::Error> {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+
+    // directories are watched instead of the logs themselves, so that logs replaced by new files are still watched
+    let mut watched_dirs = HashSet::new();
+    let mut indices = HashMap::new();
+    for (index, path) in paths.iter().enumerate() {
+        let path = path.canonicalize().map_err(|err| {
+            anyhow::format_err!("failed to resolve {}: {}", path.to_string_lossy(), err)
+        })?;
+        if let Some(dir) = path.parent()
+            && watched_dirs.insert(dir.to_path_buf())
+        {
+            watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        }
+        indices.insert(path, index);
+    }
+
+    loop {
+        let mut events = vec![receiver.recv()?];
+        events.extend(iter::from_fn(|| receiver.recv_timeout(WATCH_DEBOUNCE).ok()));
+
+        let mut changed: BTreeSet<usize> = BTreeSet::new();
+        for event in events {
+            let event = event?;
+            if matches!(
+                event.kind,
+                EventKind::Create(_)
+                    | EventKind::Modify(
+                        ModifyKind::Data(_) | ModifyKind::Name(_) | ModifyKind::Any
+                    )
+            ) {
+                changed.extend(
+                    event
+                        .paths
+                        .iter()
+                        .filter_map(|path| indices.get(path).copied()),
+                );
+            }
+        }
+        changed.into_iter().for_each(&refilter);
+    }
+}
+
+/// Log followed by --follow along with its output
+struct FollowedLog<'a> {
+    log_path: &'a Path,
+    output_path: &'a Path,
+    log: File,
+    output: File,
+    follower: stream::LogFollower<'a>,
+    /// Size of the log read so far
+    log_len: u64,
+    /// Size of the output without the footer, which the next kept messages are written over
+    output_len: u64,
+}
+
+impl<'a> FollowedLog<'a> {
+    fn open(
+        log_path: &'a Path,
+        output_path: &'a Path,
+        config: &'a Config,
+        options: FilterOptions<'a>,
+        overwrite: bool,
+    ) -> Result<Self, anyhow::Error> {
+        let log = File::open(log_path)
+            .map_err(|err| anyhow::format_err!("error while opening the input file: {}", err))?;
+        let output = create_output_file(output_path, overwrite)?;
+        Ok(Self {
+            log_path,
+            output_path,
+            log,
+            output,
+            follower: stream::LogFollower::new(config, options),
+            log_len: 0,
+            output_len: 0,
+        })
+    }
+
+    /// Filters the part of the log appended since the last call into the output. Returns the number of newly kept
+    /// messages.
+    fn poll(&mut self) -> Result<usize, anyhow::Error> {
+        let len = self
+            .log
+            .metadata()
+            .map_err(|err| anyhow::format_err!("error while reading the input file: {}", err))?
+            .len();
+        if len < self.log_len {
+            Err(anyhow::Error::msg(
+                "the log shrank, it was probably replaced by another one",
+            ))?
+        }
+        let mut input = Vec::new();
+        self.log
+            .read_to_end(&mut input)
+            .map_err(|err| anyhow::format_err!("error while reading the input file: {}", err))?;
+        self.log_len += input.len() as u64;
+
+        let kept = self.follower.stats().kept;
+        let appended = self.follower.push(&input)?;
+        if appended.is_empty() {
+            return Ok(0);
+        }
+        self.output
+            .seek(SeekFrom::Start(self.output_len))
+            .and_then(|_| self.output.write_all(&appended))
+            .and_then(|()| self.output.write_all(self.follower.footer().as_bytes()))
+            .and_then(|()| self.output.flush())
+            .map_err(|err| {
+                anyhow::format_err!(
+                    "error while writing to the output file {}: {}",
+                    self.output_path.to_string_lossy(),
+                    err
+                )
+            })?;
+        self.output_len += appended.len() as u64;
+        Ok(self.follower.stats().kept - kept)
+    }
+}
+
+/// Follows the logs at `paths` as they grow, appending newly kept messages to their outputs. Returns once every log
+/// is closed by its footer, reaches the limit of kept messages or fails, so usually runs until interrupted.
+fn follow_paths(cli: &Cli, config: &Config, options: FilterOptions<'_>, output_paths: &[PathBuf]) {
+    let report_error = |log_path: &Path, err: anyhow::Error| {
+        eprintln!("Failed to follow {}: {}", log_path.to_string_lossy(), err);
+        if cli.strict {
+            eprintln!("Encountered error in strict mode. Exiting...");
+            exit(1)
+        }
+    };
+    let mut followed: Vec<FollowedLog> = cli
+        .paths
+        .iter()
+        .zip(output_paths)
+        .filter_map(|(log_path, output_path)| {
+            FollowedLog::open(
+                log_path,
+                output_path,
+                config,
+                options,
+                cli.may_overwrite(output_path),
+            )
+            .map_err(|err| report_error(log_path, err))
+            .ok()
+        })
+        .collect();
+    println!("Following {} logs, press Ctrl-C to stop", followed.len());
+
+    while !followed.is_empty() {
+        followed.retain_mut(|log| {
+            match log.poll() {
+                Ok(0) => {}
+                Ok(kept) => println!(
+                    "Appended {} messages from {} to {}",
+                    kept,
+                    log.log_path.to_string_lossy(),
+                    log.output_path.to_string_lossy()
+                ),
+                Err(err) => {
+                    report_error(log.log_path, err);
+                    return false;
+                }
+            }
+            if log.follower.is_done() {
+                let stats = log.follower.stats();
                 println!(
-                    "Filtered chat log from {} to {} in {}ms",
-                    log_path.to_string_lossy(),
-                    output_path.to_string_lossy(),
-                    this_path_start.elapsed().as_millis()
+                    "Stopped following {}: {}, kept {} of {} messages",
+                    log.log_path.to_string_lossy(),
+                    if stats.limit_reached {
+                        "reached the limit of kept messages"
+                    } else {
+                        "the log is closed"
+                    },
+                    stats.kept,
+                    stats.total
                 );
             }
+            !log.follower.is_done()
+        });
+        sleep(FOLLOW_INTERVAL);
+    }
+}
+
+/// Prints the outcome of processing a single log. Exits the program if it failed in strict mode.
+fn report_processed(cli: &Cli, log_name: &str, output_path: &Path, processed: ProcessedLog) {
+    match processed.result {
+        Ok(None) => {
+            progress!(
+                cli.stdout,
+                "Skipped {}: document doesn't match the required document regex",
+                log_name
+            );
+        }
+        Ok(Some(stats)) if cli.count => {
+            // both are reported unless only one of them is requested
+            let mut counts = Vec::new();
+            if cli.count_messages || !cli.count_lines {
+                counts.push(format!(
+                    "kept {} / dropped {}{} / total {}{}",
+                    stats.kept,
+                    partial_prefix(&stats),
+                    stats.total - stats.kept,
+                    partial_prefix(&stats),
+                    stats.total
+                ));
+            }
+            if cli.count_lines || !cli.count_messages {
+                counts.push(format!("{} lines", stats.lines_out));
+            }
+            println!("{}: {}", log_name, counts.join(", "));
+        }
+        Ok(Some(stats)) => {
+            let destination = match &cli.tar {
+                _ if cli.stdout => "the standard output".to_string(),
+                Some(tar_path) => format!(
+                    "{} in {}",
+                    entry_name(output_path).to_string_lossy(),
+                    tar_path.to_string_lossy()
+                ),
+                None => output_path.to_string_lossy().into_owned(),
+            };
+            progress!(
+                cli.stdout,
+                "Filtered chat log from {} to {} in {}ms: kept {} of {}{} messages, {} of {}{} bytes",
+                log_name,
+                destination,
+                processed.elapsed.as_millis(),
+                stats.kept,
+                partial_prefix(&stats),
+                stats.total,
+                stats.bytes_out,
+                partial_prefix(&stats),
+                stats.bytes_in
+            );
+            if cli.format.len() > 1 && cli.tar.is_none() {
+                for format in &cli.format[1..] {
+                    progress!(
+                        cli.stdout,
+                        "  also written to {}",
+                        with_format_extension(output_path, *format).to_string_lossy()
+                    );
+                }
+            }
+            if let Some(split_size) = cli
+                .split_size
+                .filter(|_| cli.format.contains(&OutputFormat::Html))
+            {
+                progress!(
+                    cli.stdout,
+                    "  written in numbered parts of up to {} bytes",
+                    split_size
+                );
+            }
+            if stats.whole_document && cli.parser == BuiltinParser::Tg {
+                progress!(
+                    cli.stdout,
+                    "  warning: no {} was found, the whole document was filtered",
+                    CHAT_OPENING
+                );
+            }
+            if let Some(encoding) = stats.encoding {
+                progress!(cli.stdout, "  detected {} encoding", encoding);
+            }
+            if stats.lossy {
+                progress!(
+                    cli.stdout,
+                    "  warning: the log isn't valid UTF-8, invalid bytes were replaced"
+                );
+            }
+            if stats.skipped_too_long > 0 {
+                progress!(
+                    cli.stdout,
+                    "  {} messages were skipped for exceeding the maximum message length",
+                    stats.skipped_too_long
+                );
+            }
+            if stats.limit_reached {
+                progress!(
+                    cli.stdout,
+                    "  reached the limit of {} kept messages, the rest of the log was {}",
+                    stats.kept,
+                    if stats.read_partially {
+                        "not read"
+                    } else {
+                        "skipped"
+                    }
+                );
+            }
+            if stats.deduplicated > 0 {
+                progress!(
+                    cli.stdout,
+                    "  {} repeated messages were collapsed",
+                    stats.deduplicated
+                );
+            }
+            if stats.merge_duplicates > 0 {
+                progress!(
+                    cli.stdout,
+                    "  {} messages repeating the earlier merged logs were removed",
+                    stats.merge_duplicates
+                );
+            }
+            if stats.omitted_by_budget > 0 {
+                progress!(
+                    cli.stdout,
+                    "  {} matching messages were omitted to fit the output budget",
+                    stats.omitted_by_budget
+                );
+            }
+            if cli.profile {
+                progress!(cli.stdout, "  {}", processed.timings);
+            }
+        }
+        Err(err) => {
+            eprintln!("Failed to process {}: {}", log_name, err);
+            if cli.strict {
+                eprintln!("Encountered error in strict mode. Exiting...");
+                exit(1)
+            }
+        }
+    }
+}
+
+/// Prefix of totals, which count only the part of the log read before the limit of kept messages was reached
+fn partial_prefix(stats: &FilterStats) -> &'static str {
+    if stats.read_partially {
+        "at least "
+    } else {
+        ""
+    }
+}
+
+/// Prints the visible text of messages of each input, which `config` keeps, highlighting the parts matched by its
+/// include patterns. Fails only if the patterns can't be compiled, unreadable inputs are reported and skipped unless
+/// the mode is strict.
+fn run_preview(
+    cli: &Cli,
+    config: &Config,
+    options: ProcessOptions<'_>,
+) -> Result<(), anyhow::Error> {
+    let finders = config.include_finders()?;
+    let colored = io::stdout().is_terminal();
+    for log_path in &cli.paths {
+        let decoded = match read_chat_log(log_path, options.read) {
+            Ok(decoded) => decoded,
             Err(err) => {
-                eprintln!("Failed to process {}: {}", log_path.to_string_lossy(), err);
+                eprintln!("Failed to read {}: {}", log_path.to_string_lossy(), err);
                 if cli.strict {
                     eprintln!("Encountered error in strict mode. Exiting...");
                     exit(1)
-                } else {
-                    continue;
                 }
+                continue;
+            }
+        };
+        if !config.matches_document(&decoded.chat_log) {
+            continue;
+        }
+
+        println!("{}:", log_path.to_string_lossy());
+        for message in matching_messages(&decoded.chat_log, config)? {
+            println!("  {}", highlight(&plain_text(message), &finders, colored));
+        }
+    }
+    Ok(())
+}
+
+/// Wraps the parts of `text` found by any of `finders` in ANSI colors, if `colored` is set. Overlapping parts are
+/// highlighted as one.
+fn highlight(text: &str, finders: &[Regex], colored: bool) -> String {
+    if !colored {
+        return text.to_string();
+    }
+    let mut spans: Vec<Range<usize>> = finders
+        .iter()
+        .flat_map(|finder| finder.find_iter(text).map(|found| found.range()))
+        .collect();
+    spans.sort_by_key(|span| span.start);
+
+    let mut highlighted = String::with_capacity(text.len());
+    let mut position = 0;
+    for span in spans {
+        if span.end <= position {
+            continue;
+        }
+        let start = span.start.max(position);
+        highlighted.push_str(&text[position..start]);
+        highlighted.push_str(&(&text[start..span.end]).red().bold().to_string());
+        position = span.end;
+    }
+    highlighted.push_str(&text[position..]);
+    highlighted
+}
+
+/// Checks the structure of each input and prints the problems found. Returns `false` if any input has problems or
+/// can't be read.
+fn run_validation(cli: &Cli, options: ProcessOptions<'_>) -> bool {
+    let mut failed = 0;
+    for log_path in &cli.paths {
+        let problems = match read_chat_log(log_path, options.read) {
+            Ok(decoded) => {
+                let mut problems = validate_chat_log(&decoded.chat_log);
+                if decoded.lossy {
+                    problems.push("the log isn't valid UTF-8".to_string());
+                }
+                problems
             }
+            Err(err) => vec![err.to_string()],
+        };
+        if problems.is_empty() {
+            println!("OK: {}", log_path.to_string_lossy());
+            continue;
+        }
+        failed += 1;
+        println!("Invalid: {}", log_path.to_string_lossy());
+        for problem in problems {
+            println!("  {}", problem);
         }
     }
 
     println!(
-        "Filtered {} logs in {}ms",
+        "Validated {} logs, {} of them invalid",
         cli.paths.len(),
-        start.elapsed().as_millis()
+        failed
     );
+    failed == 0
+}
+
+/// Prints a report on problems, which would occur while processing the inputs. Returns `false` if any of them is
+/// blocking.
+fn run_preflight(
+    cli: &Cli,
+    config: &Config,
+    output_paths: &[PathBuf],
+    collision_error: Option<anyhow::Error>,
+) -> bool {
+    let mut blocking = 0;
+    let mut warnings = 0;
+    let mut report = |problem: String, is_blocking: bool| {
+        if is_blocking {
+            blocking += 1;
+            println!("  error: {}", problem);
+        } else {
+            warnings += 1;
+            println!("  warning: {}", problem);
+        }
+    };
+
+    println!("Preflight report:");
+
+    if let Err(err) = config.validate() {
+        report(format!("invalid config: {}", err), true);
+    }
+
+    let mut total_size = 0;
+    for log_path in &cli.paths {
+        match File::open(log_path).and_then(|file| file.metadata()) {
+            Ok(metadata) if metadata.is_file() => total_size += metadata.len(),
+            Ok(_) => report(
+                format!("input {} is not a file", log_path.to_string_lossy()),
+                cli.strict,
+            ),
+            Err(err) => report(
+                format!(
+                    "input {} is not readable: {}",
+                    log_path.to_string_lossy(),
+                    err
+                ),
+                cli.strict,
+            ),
+        }
+    }
+
+    // merging writes only the merged output, so per-input outputs may collide or exist
+    if let Some(err) = collision_error.filter(|_| !cli.stdout && !cli.count && cli.merge.is_none())
+    {
+        report(err.to_string(), true);
+    }
+
+    match (&cli.merge, &cli.tar) {
+        _ if cli.stdout || cli.count => {}
+        (Some(merge_path), _) if merge_path.exists() && !cli.may_overwrite(merge_path) => report(
+            format!(
+                "merged output {} already exists and may not be overwritten",
+                merge_path.to_string_lossy()
+            ),
+            true,
+        ),
+        (Some(_), _) => {}
+        (None, Some(tar_path)) if tar_path.exists() && !cli.may_overwrite(tar_path) => report(
+            format!(
+                "archive {} already exists and may not be overwritten",
+                tar_path.to_string_lossy()
+            ),
+            true,
+        ),
+        (None, Some(_)) => {}
+        (None, None) => {
+            for output_path in output_paths
+                .iter()
+                .filter(|output_path| output_path.exists() && !cli.may_overwrite(output_path))
+            {
+                report(
+                    format!(
+                        "output {} already exists and may not be overwritten",
+                        output_path.to_string_lossy()
+                    ),
+                    cli.strict,
+                );
+            }
+        }
+    }
+
+    println!(
+        "Total input size: {} bytes in {} files",
+        total_size,
+        cli.paths.len()
+    );
+    println!(
+        "Preflight {} with {} errors and {} warnings",
+        if blocking == 0 { "passed" } else { "failed" },
+        blocking,
+        warnings
+    );
+
+    blocking == 0
+}
+
+/// Expands `path` into the sorted paths matching it, if it contains glob metacharacters. Other paths are returned as
+/// is, whether they exist or not, as are existing paths with metacharacters in their names, like "[round 1].html".
+/// Fails if the pattern is invalid or matches nothing.
+fn expand_glob(path: &Path) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let Some(pattern) = path
+        .to_str()
+        .filter(|pattern| pattern.contains(['*', '?', '[']) && !path.exists())
+    else {
+        return Ok(vec![path.to_path_buf()]);
+    };
+
+    let mut matches = Vec::new();
+    for entry in glob::glob(pattern)
+        .map_err(|err| anyhow::format_err!("Invalid glob pattern {}: {}", pattern, err))?
+    {
+        match entry {
+            Ok(matched_path) => matches.push(matched_path),
+            Err(err) => eprintln!("Skipped a path matching {}: {}", pattern, err),
+        }
+    }
+
+    if matches.is_empty() {
+        Err(anyhow::format_err!(
+            "Pattern {} didn't match any files",
+            pattern
+        ))?
+    }
+    Ok(matches)
+}
+
+/// Recursively collects files in `dir`, which names match `file_glob`, along with their parent directories relative to
+/// `dir`. Gzipped files match if their names do without the ".gz" extension, so "*.html" finds "game.html.gz" too.
+/// Symlinked directories are followed, but each directory is visited once, so symlink loops end. Unreadable
+/// directories are reported to `on_error` and skipped.
+fn discover_logs(
+    dir: &Path,
+    file_glob: &Pattern,
+    mut on_error: impl FnMut(anyhow::Error),
+) -> Vec<(PathBuf, PathBuf)> {
+    let mut logs = Vec::new();
+    let mut visited: HashSet<PathBuf> = HashSet::new();
+    let mut pending_dirs = vec![PathBuf::new()];
+
+    while let Some(subdir) = pending_dirs.pop() {
+        let current_dir = dir.join(&subdir);
+        match current_dir.canonicalize() {
+            Ok(canonical_dir) => {
+                if !visited.insert(canonical_dir) {
+                    continue;
+                }
+            }
+            Err(err) => {
+                on_error(anyhow::format_err!(
+                    "Failed to resolve the directory {}: {}",
+                    current_dir.to_string_lossy(),
+                    err
+                ));
+                continue;
+            }
+        }
+
+        let entries = match current_dir.read_dir() {
+            Ok(entries) => entries,
+            Err(err) => {
+                on_error(anyhow::format_err!(
+                    "Failed to read the directory {}: {}",
+                    current_dir.to_string_lossy(),
+                    err
+                ));
+                continue;
+            }
+        };
+        let mut entry_paths: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).collect();
+        // reversed, so that subdirectories are popped in order
+        entry_paths.sort_unstable_by(|a, b| b.cmp(a));
+
+        for entry_path in entry_paths {
+            let Some(file_name) = entry_path.file_name() else {
+                continue;
+            };
+            if entry_path.is_dir() {
+                pending_dirs.push(subdir.join(file_name));
+            } else if matches_file_glob(file_glob, Path::new(file_name)) {
+                logs.push((entry_path.clone(), subdir.clone()));
+            }
+        }
+    }
+
+    logs.sort();
+    logs
+}
+
+/// Whether the file name matches the glob, either as is or without the ".gz" extension of a gzipped file
+fn matches_file_glob(file_glob: &Pattern, file_name: &Path) -> bool {
+    file_glob.matches_path(file_name)
+        || file_name
+            .extension()
+            .is_some_and(|extension| extension == "gz")
+            && file_name
+                .file_stem()
+                .is_some_and(|stem| file_glob.matches_path(Path::new(stem)))
 }
 
 fn get_path_for_output(
     index: usize,
     outputs: &[PathBuf],
     path: &Path,
+    subdir: &Path,
     base_dir: &Option<PathBuf>,
+    gzip: bool,
 ) -> PathBuf {
     if let Some(output) = outputs.get(index) {
         return output.clone();
     }
-    let base_dir = match &base_dir {
-        Some(dir) => dir.to_string_lossy().trim_end_matches("/").to_string(),
-        None => ".".to_string(),
-    };
+    // collecting components drops redundant and trailing separators, joining keeps separators platform-correct. "\"
+    // isn't a separator on Unix, so trailing ones are trimmed by hand.
+    let base_dir: PathBuf = match &base_dir {
+        Some(dir) => match dir.to_str().map(|dir| dir.trim_end_matches(['/', '\\'])) {
+            Some(trimmed) if !trimmed.is_empty() => Path::new(trimmed).components().collect(),
+            _ => dir.components().collect(),
+        },
+        None => PathBuf::from("."),
+    }
+    .join(subdir);
+    // compressed inputs are decompressed, so "game.html.gz" becomes "filtered_game.html"
+    let file_name = match path.extension() {
+        Some(extension) if extension == "gz" => path.file_stem(),
+        _ => path.file_name(),
+    }
+    .map(|file_name| file_name.to_string_lossy())
+    .unwrap_or(format!("file_name_error{}", index).into());
+
+    if gzip {
+        base_dir.join(format!("filtered_{}.gz", file_name))
+    } else {
+        base_dir.join(format!("filtered_{}", file_name))
+    }
+}
+
+/// Detects inputs which resolve to the same output path. Depending on `policy`, either returns an error naming the
+/// colliding inputs, or renames later outputs by appending the first free index to their file stems.
+fn resolve_output_collisions(
+    paths: &[PathBuf],
+    output_paths: &mut [PathBuf],
+    policy: CollisionPolicy,
+) -> Result<(), anyhow::Error> {
+    let mut taken: HashMap<PathBuf, usize> = HashMap::new();
+
+    for index in 0..output_paths.len() {
+        if let Some(&first) = taken.get(&output_paths[index]) {
+            match policy {
+                CollisionPolicy::Error => Err(anyhow::format_err!(
+                    "{} and {} both resolve to the output {}",
+                    paths[first].to_string_lossy(),
+                    paths[index].to_string_lossy(),
+                    output_paths[index].to_string_lossy()
+                ))?,
+                CollisionPolicy::Index => {
+                    output_paths[index] = (1..)
+                        .map(|suffix| with_index_suffix(&output_paths[index], suffix))
+                        .find(|candidate| !taken.contains_key(candidate))
+                        .expect("infinite iterator always yields a free index");
+                }
+            }
+        }
+        taken.insert(output_paths[index].clone(), index);
+    }
+
+    Ok(())
+}
+
+/// Turns "dir/filtered_game.html" into "dir/filtered_game_{index}.html"
+fn with_index_suffix(path: &Path, index: usize) -> PathBuf {
+    with_suffix(path, &format!("_{}", index))
+}
+
+/// Turns "filtered_game.html" into "filtered_game.part001.html" for the part `number`
+fn with_part_suffix(path: &Path, number: usize) -> PathBuf {
+    with_suffix(path, &format!(".part{:03}", number))
+}
+
+/// Replaces the extension of the output path with the one of `format`, keeping the ".gz" extension of gzipped outputs
+/// last, so "filtered_game.html.gz" becomes "filtered_game.json.gz" for JSON
+fn with_format_extension(path: &Path, format: OutputFormat) -> PathBuf {
     let file_name = path
         .file_name()
         .map(|file_name| file_name.to_string_lossy())
-        .unwrap_or(format!("file_name_error{}", index).into());
+        .unwrap_or_default();
+    let (name, gzip_extension) = match file_name.strip_suffix(".gz") {
+        Some(name) => (name, ".gz"),
+        None => (&*file_name, ""),
+    };
+    let stem = match name.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem,
+        _ => name,
+    };
+    path.with_file_name(format!("{}.{}{}", stem, format.extension(), gzip_extension))
+}
 
-    PathBuf::from(format!("{}/filtered_{}", base_dir, file_name))
+/// Inserts `suffix` into the file name before its extension. The ".gz" extension of gzipped outputs stays last, so
+/// "filtered_game.html.gz" becomes "filtered_game{suffix}.html.gz".
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let file_name = path
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy())
+        .unwrap_or_default();
+    let (name, gzip_extension) = match file_name.strip_suffix(".gz") {
+        Some(name) => (name, ".gz"),
+        None => (&*file_name, ""),
+    };
+    let suffixed_name = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => {
+            format!("{}{}.{}{}", stem, suffix, extension, gzip_extension)
+        }
+        _ => format!("{}{}{}", name, suffix, gzip_extension),
+    };
+    path.with_file_name(suffixed_name)
 }
 
+/// Filters the log at `path` into `output_path`, into the `tar` entry named `output_path` if archive is given, or into
+/// the standard output if `options` say so. With several formats, the log is filtered into each of them at once and
+/// written next to `output_path`, named by their extensions. Logs are streamed, unless some of the options need the
+/// whole log in memory.
+/// Time spent in each stage is added to `timings`. Returns the filter stats, or `None` if the log was skipped by the
+/// document regex.
 fn process_path(
-    path: &PathBuf,
-    output_path: &PathBuf,
+    path: &Path,
+    output_path: &Path,
     config: &Config,
+    options: ProcessOptions<'_>,
     overwrite: bool,
+    mut tar: Option<&mut TarOutput>,
+    timings: &mut StageTimings,
+) -> Result<Option<FilterStats>, anyhow::Error> {
+    if can_stream(config, options, tar.as_deref()) {
+        return stream_path(path, output_path, config, options, overwrite, timings).map(Some);
+    }
+
+    let Some(filtered) = filter_path(path, config, options, timings)? else {
+        return Ok(None);
+    };
+
+    // stats of the first format are reported, others differ only by the size of the output
+    let mut stats = None;
+    let writing_start = Instant::now();
+    for (format, (filtered_chat_log, format_stats)) in options.formats().iter().zip(filtered) {
+        stats.get_or_insert(format_stats);
+        if options.count_only {
+            continue;
+        }
+        let format_output_path = if options.formats().len() > 1 {
+            Cow::Owned(with_format_extension(output_path, *format))
+        } else {
+            Cow::Borrowed(output_path)
+        };
+        let format_options = ProcessOptions {
+            split_size: options.split_size.filter(|_| *format == OutputFormat::Html),
+            ..options
+        };
+        write_output(
+            filtered_chat_log,
+            &format_output_path,
+            format_options,
+            overwrite,
+            tar.as_deref_mut(),
+            timings,
+        )?;
+    }
+    if !options.count_only {
+        timings.writing = writing_start.elapsed();
+    }
+    Ok(stats)
+}
+
+/// Writes the filtered log into `output_path`, into the `tar` entry named `output_path` if archive is given, or into
+/// the standard output if `options` say so. If they set the split size, the log is written in parts named after
+/// `output_path`.
+fn write_output(
+    filtered_chat_log: String,
+    output_path: &Path,
+    options: ProcessOptions<'_>,
+    overwrite: bool,
+    mut tar: Option<&mut TarOutput>,
+    timings: &mut StageTimings,
 ) -> Result<(), anyhow::Error> {
-    let chat_log = read_to_string(path)
-        .map_err(|err| anyhow::format_err!("error while reading the input file: {}", err))?;
+    let writing_start = Instant::now();
 
-    let filtered_chat_log = filter_chat_log(chat_log, config).unwrap_or_else(|err| {
-        eprintln!("filter error: {}", err);
-        exit(1);
+    if let Some(split_size) = options.split_size {
+        let parts = split_chat_log_by_size(&filtered_chat_log, split_size);
+        for (index, part) in parts.into_iter().enumerate() {
+            write_output(
+                part,
+                &with_part_suffix(output_path, index + 1),
+                ProcessOptions {
+                    split_size: None,
+                    ..options
+                },
+                overwrite,
+                tar.as_deref_mut(),
+                timings,
+            )?;
+        }
+        timings.writing = writing_start.elapsed();
+        return Ok(());
+    }
+
+    if let Some(tar) = tar {
+        tar.append(output_path, &filtered_chat_log)?;
+        timings.writing = writing_start.elapsed();
+        return Ok(());
+    }
+
+    let contents = if options.gzip_output {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(filtered_chat_log.as_bytes())
+            .and_then(|()| encoder.finish())
+            .map_err(|err| anyhow::format_err!("failed to compress the output: {}", err))?
+    } else {
+        filtered_chat_log.into_bytes()
+    };
+
+    if options.to_stdout {
+        let mut stdout = io::stdout().lock();
+        stdout
+            .write_all(&contents)
+            .and_then(|()| stdout.flush())
+            .map_err(|err| {
+                anyhow::format_err!("error while writing to the standard output: {}", err)
+            })?;
+        timings.writing = writing_start.elapsed();
+        return Ok(());
+    }
+
+    if is_fifo(output_path) {
+        write_to_fifo(output_path, &contents)?;
+        timings.writing = writing_start.elapsed();
+        return Ok(());
+    }
+
+    let mut output_file = create_output_file(output_path, overwrite)?;
+    output_file.write_all(&contents).map_err(|err| {
+        anyhow::format_err!(
+            "error while writing to the output file {}: {}",
+            output_path.to_string_lossy(),
+            err
+        )
+    })?;
+    drop(output_file);
+
+    if let Some(mode) = options.output_mode {
+        set_mode(output_path, mode)?;
+    }
+    timings.writing = writing_start.elapsed();
+
+    Ok(())
+}
+
+/// Filters the log at `path` like `process_path`, but without loading the whole log into memory. Output files are
+/// written under a temporary name in the same directory and renamed over `output_path` once the whole log is filtered,
+/// so that a log failing midway doesn't leave a partially written output. Pipes and the standard output are written
+/// directly.
+fn stream_path(
+    path: &Path,
+    output_path: &Path,
+    config: &Config,
+    options: ProcessOptions<'_>,
+    overwrite: bool,
+    timings: &mut StageTimings,
+) -> Result<FilterStats, anyhow::Error> {
+    // reading, matching and writing are interleaved, so all of the time is counted as matching
+    let matching_start = Instant::now();
+
+    let mut reader = open_chat_log(path, options.read.retries)?;
+
+    let header = stream::read_header(&mut reader)?;
+
+    let is_file = !options.count_only && !options.to_stdout && !is_fifo(output_path);
+    let temp_path = temp_output_path(output_path);
+    let mut output: Box<dyn Write> = if options.count_only {
+        Box::new(io::sink())
+    } else if options.to_stdout {
+        Box::new(io::stdout().lock())
+    } else if !is_file {
+        Box::new(
+            OpenOptions::new()
+                .write(true)
+                .open(output_path)
+                .map_err(|err| {
+                    anyhow::format_err!(
+                        "failed to open the pipe {}: {}",
+                        output_path.to_string_lossy(),
+                        err
+                    )
+                })?,
+        )
+    } else {
+        // fails before filtering anything, rather than after
+        check_output_free(output_path, overwrite)?;
+        Box::new(create_output_file(&temp_path, false)?)
+    };
+
+    let result = if options.gzip_output {
+        let mut encoder = GzEncoder::new(&mut output, Compression::default());
+        stream::stream_chat_log(reader, header, &mut encoder, config, options.filter).and_then(
+            |stats| {
+                encoder
+                    .finish()
+                    .map_err(|err| anyhow::format_err!("failed to compress the output: {}", err))?;
+                Ok(stats)
+            },
+        )
+    } else {
+        stream::stream_chat_log(reader, header, &mut output, config, options.filter)
+    };
+    drop(output);
+
+    let result = result.and_then(|stats| {
+        if is_file {
+            rename_output(&temp_path, output_path, overwrite)?;
+        }
+        Ok(stats)
     });
+    if is_file && result.is_err() {
+        // the error is reported by the caller, the temporary file is of no use to anyone
+        let _ = remove_file(&temp_path);
+    }
+    let stats = result?;
+
+    if is_file && let Some(mode) = options.output_mode {
+        set_mode(output_path, mode)?;
+    }
+    timings.matching = matching_start.elapsed();
+
+    Ok(stats)
+}
 
+/// Source log of a message of the merged output along with the position of the message in it
+type KeptSource<'p> = (&'p Path, usize);
+
+/// Filters the logs at `paths` merged together into `merge_path`. Logs, which fail to be read, are passed to `on_error`
+/// and left out, as are the ones not matching the document regex. Returns the stats along with the source log and the
+/// position in it of each kept message.
+fn merge_paths<'p>(
+    paths: &'p [PathBuf],
+    merge_path: &Path,
+    config: &Config,
+    options: ProcessOptions<'_>,
+    overwrite: bool,
+    on_error: impl Fn(anyhow::Error),
+    timings: &mut StageTimings,
+) -> Result<(FilterStats, Vec<KeptSource<'p>>), anyhow::Error> {
+    let reading_start = Instant::now();
+    let mut chat_logs = Vec::with_capacity(paths.len());
+    let mut merged_paths = Vec::with_capacity(paths.len());
+    let mut lossy = false;
+    for path in paths {
+        match read_chat_log(path, options.read) {
+            Ok(decoded) if config.matches_document(&decoded.chat_log) => {
+                lossy |= decoded.lossy;
+                chat_logs.push(decoded.chat_log);
+                merged_paths.push(path.as_path());
+            }
+            Ok(_) => println!(
+                "Skipped {}: document doesn't match the required document regex",
+                path.to_string_lossy()
+            ),
+            Err(err) => on_error(anyhow::format_err!(
+                "Failed to read {}: {}",
+                path.to_string_lossy(),
+                err
+            )),
+        }
+    }
+    if chat_logs.is_empty() {
+        Err(anyhow::format_err!("none of the logs could be merged"))?
+    }
+    let merged = merge_chat_logs(&chat_logs, options.filter.dedup);
+    timings.reading = reading_start.elapsed();
+
+    let (filtered_chat_log, stats, kept_sources) =
+        filter_merged_chat_log(&merged, config, options.filter, timings)
+            .map_err(|err| anyhow::format_err!("filter error: {}", err))?;
+    write_output(
+        filtered_chat_log,
+        merge_path,
+        options,
+        overwrite,
+        None,
+        timings,
+    )?;
+
+    let kept_sources = kept_sources
+        .into_iter()
+        .map(|source| (merged_paths[source.log], source.index))
+        .collect();
+    Ok((FilterStats { lossy, ..stats }, kept_sources))
+}
+
+/// Entry of the JSON merge index
+#[derive(Serialize)]
+struct MergeIndexEntry<'a> {
+    message: usize,
+    file: Cow<'a, str>,
+    index: usize,
+}
+
+/// Writes the source log and the position in it of each message of the merged output, as JSON if `index_path` has
+/// the ".json" extension and as CSV otherwise. Messages and their positions are numbered from 1.
+fn write_merge_index(
+    index_path: &Path,
+    kept_sources: &[KeptSource<'_>],
+    overwrite: bool,
+) -> Result<(), anyhow::Error> {
+    let contents = if is_json_path(index_path) {
+        let entries: Vec<_> = kept_sources
+            .iter()
+            .enumerate()
+            .map(|(message, (path, index))| MergeIndexEntry {
+                message: message + 1,
+                file: path.to_string_lossy(),
+                index: index + 1,
+            })
+            .collect();
+        serde_json::to_string_pretty(&entries)?
+    } else {
+        let mut csv = String::from("message,file,index\n");
+        for (message, (path, index)) in kept_sources.iter().enumerate() {
+            csv.push_str(&format!(
+                "{},{},{}\n",
+                message + 1,
+                csv_field(&path.to_string_lossy()),
+                index + 1
+            ));
+        }
+        csv
+    };
+    create_output_file(index_path, overwrite)?
+        .write_all(contents.as_bytes())
+        .map_err(|err| {
+            anyhow::format_err!(
+                "error while writing to the merge index {}: {}",
+                index_path.to_string_lossy(),
+                err
+            )
+        })
+}
+
+/// Writes the mapping of anonymized senders to their pseudonyms into the file given by --anon-map, if any. Exits the
+/// program if it fails.
+fn write_anon_map_if_requested(cli: &Cli, anonymizer: Option<&Anonymizer>) {
+    let (Some(map_path), Some(anonymizer)) = (&cli.anon_map, anonymizer) else {
+        return;
+    };
+    let mapping = anonymizer.mapping();
+    let contents = if is_json_path(map_path) {
+        let entries: Vec<_> = mapping
+            .iter()
+            .map(|(sender, pseudonym)| AnonMapEntry { sender, pseudonym })
+            .collect();
+        serde_json::to_string_pretty(&entries).map_err(anyhow::Error::from)
+    } else {
+        let mut csv = String::from("sender,pseudonym\n");
+        for (sender, pseudonym) in &mapping {
+            csv.push_str(&format!("{},{}\n", csv_field(sender), pseudonym));
+        }
+        Ok(csv)
+    };
+    contents
+        .and_then(|contents| {
+            create_output_file(map_path, cli.may_overwrite(map_path))?
+                .write_all(contents.as_bytes())
+                .map_err(anyhow::Error::from)
+        })
+        .unwrap_or_else(|err| {
+            eprintln!(
+                "Failed to write the pseudonyms to {}: {}",
+                map_path.to_string_lossy(),
+                err
+            );
+            exit(1);
+        });
+    progress!(
+        cli.stdout,
+        "Wrote pseudonyms of {} senders to {}",
+        mapping.len(),
+        map_path.to_string_lossy()
+    );
+}
+
+/// Entry of the JSON mapping of senders to their pseudonyms
+#[derive(Serialize)]
+struct AnonMapEntry<'a> {
+    sender: &'a str,
+    pseudonym: &'a str,
+}
+
+/// Whether side files at `path`, like the merge index, are written as JSON rather than CSV
+fn is_json_path(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("json"))
+}
+
+/// Quotes the CSV field if it contains commas, quotes or line breaks
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+/// Whether the log can be streamed instead of being loaded into memory, which is impossible if any option needs the
+/// whole log
+fn can_stream(config: &Config, options: ProcessOptions<'_>, tar: Option<&TarOutput>) -> bool {
+    tar.is_none()
+        && !options.read.detect_encoding
+        && options.split_size.is_none()
+        && options.formats().len() == 1
+        && ss13_mlogfilter::can_stream(config, options.filter)
+}
+
+/// Creates the output file along with missing parent directories
+fn create_output_file(output_path: &Path, overwrite: bool) -> Result<File, anyhow::Error> {
     let parent_dir = output_path.parent().ok_or(anyhow::format_err!(
         "invalid output path {}",
         output_path.to_string_lossy()
@@ -190,55 +1703,351 @@ fn process_path(
         )
     })?;
 
-    let mut output_file = OpenOptions::new()
+    OpenOptions::new()
         .write(true)
         .create_new(!overwrite)
         .create(overwrite)
         .truncate(overwrite)
         .open(output_path)
-        .unwrap_or_else(|err| {
-            eprintln!(
-                "error while creating the output file in {}: {}",
-                path.to_string_lossy(),
-                err
-            );
-            exit(1);
-        });
-
-    output_file
-        .write_all(filtered_chat_log.as_bytes())
-        .unwrap_or_else(|err| {
-            eprintln!(
-                "error while writing to the output file in {}: {}",
+        .map_err(|err| {
+            anyhow::format_err!(
+                "error while creating the output file {}: {}",
                 output_path.to_string_lossy(),
                 err
-            );
-            exit(1);
-        });
-
-    Ok(())
+            )
+        })
 }
 
-fn filter_chat_log(chat_log: String, config: &Config) -> Result<String, anyhow::Error> {
-    let mut output = String::with_capacity(chat_log.len());
-    let parts: Vec<&str> = chat_log.split_inclusive("<div class=\"Chat\">").collect();
-    if parts.len() != 2 {
+/// Fails if the output file exists and may not be overwritten
+fn check_output_free(output_path: &Path, overwrite: bool) -> Result<(), anyhow::Error> {
+    if !overwrite && output_path.exists() {
         Err(anyhow::format_err!(
-            "Expected 1 \"<div class=\"Chat\">\", but found {}",
-            parts.len() - 1
+            "error while creating the output file {}: {}",
+            output_path.to_string_lossy(),
+            io::Error::from(ErrorKind::AlreadyExists)
         ))?
     }
-    output.push_str(parts[0]);
+    Ok(())
+}
 
-    let chat_messages = parts[1].replace("</div>\n</body>\n</html>", "");
+/// Path of the temporary file in the directory of `output_path`, which the output is written to before being renamed,
+/// e.g. "out/.filtered_game.html.1234.tmp"
+fn temp_output_path(output_path: &Path) -> PathBuf {
+    let file_name = output_path
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy())
+        .unwrap_or_default();
+    output_path.with_file_name(format!(".{}.{}.tmp", file_name, process::id()))
+}
 
-    for message in chat_messages.split_inclusive("<div class=\"ChatMessage\"") {
-        if config.matches(message)? {
-            output.push_str(message);
+/// Renames the fully written temporary file over the output file
+fn rename_output(
+    temp_path: &Path,
+    output_path: &Path,
+    overwrite: bool,
+) -> Result<(), anyhow::Error> {
+    // the output may have been created while the log was filtered
+    check_output_free(output_path, overwrite)?;
+    rename(temp_path, output_path).map_err(|err| {
+        anyhow::format_err!(
+            "error while moving the output file to {}: {}",
+            output_path.to_string_lossy(),
+            err
+        )
+    })
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> Result<(), anyhow::Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).map_err(|err| {
+        anyhow::format_err!(
+            "failed to set permissions of {} to {:o}: {}",
+            path.to_string_lossy(),
+            mode,
+            err
+        )
+    })
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> Result<(), anyhow::Error> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_fifo(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_fifo())
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_path: &Path) -> bool {
+    false
+}
+
+/// Writes the filtered log into an existing named pipe. Opening blocks until a reader connects. A reader disconnecting
+/// midway fails only this log instead of exiting.
+fn write_to_fifo(fifo_path: &Path, contents: &[u8]) -> Result<(), anyhow::Error> {
+    let mut fifo = OpenOptions::new()
+        .write(true)
+        .open(fifo_path)
+        .map_err(|err| {
+            anyhow::format_err!(
+                "failed to open the pipe {}: {}",
+                fifo_path.to_string_lossy(),
+                err
+            )
+        })?;
+
+    fifo.write_all(contents)
+        .and_then(|()| fifo.flush())
+        .map_err(|err| match err.kind() {
+            ErrorKind::BrokenPipe => anyhow::format_err!(
+                "reader of the pipe {} disconnected before the log was fully written",
+                fifo_path.to_string_lossy()
+            ),
+            _ => anyhow::format_err!(
+                "error while writing to the pipe {}: {}",
+                fifo_path.to_string_lossy(),
+                err
+            ),
+        })
+}
+
+/// Reads and filters the log at `path` into each of the formats, returning the filtered logs and their stats. Returns
+/// `None` if the log doesn't match the document regex.
+fn filter_path(
+    path: &Path,
+    config: &Config,
+    options: ProcessOptions<'_>,
+    timings: &mut StageTimings,
+) -> Result<Option<Vec<(String, FilterStats)>>, anyhow::Error> {
+    let reading_start = Instant::now();
+    let decoded = read_chat_log(path, options.read)?;
+    timings.reading = reading_start.elapsed();
+
+    if !config.matches_document(&decoded.chat_log) {
+        return Ok(None);
+    }
+
+    let filtered = filter_chat_log_in_formats(
+        &decoded.chat_log,
+        config,
+        options.filter,
+        options.formats(),
+        timings,
+    )
+    .map_err(|err| anyhow::format_err!("filter error: {}", err))?;
+
+    Ok(Some(
+        filtered
+            .into_iter()
+            .map(|(filtered_chat_log, stats)| {
+                (
+                    filtered_chat_log,
+                    FilterStats {
+                        encoding: decoded.encoding,
+                        lossy: decoded.lossy,
+                        ..stats
+                    },
+                )
+            })
+            .collect(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_files::TestFiles;
+
+    fn default_output(out_dir: Option<&str>, input: &str) -> PathBuf {
+        get_path_for_output(
+            0,
+            &[],
+            Path::new(input),
+            Path::new(""),
+            &out_dir.map(PathBuf::from),
+            false,
+        )
+    }
+
+    #[test]
+    fn output_in_out_dir_with_slashes() {
+        let expected = Path::new("out").join("logs").join("filtered_game.html");
+        assert_eq!(default_output(Some("out/logs"), "game.html"), expected);
+        assert_eq!(default_output(Some("out/logs/"), "game.html"), expected);
+        assert_eq!(default_output(Some("out//logs//"), "a/game.html"), expected);
+    }
+
+    #[test]
+    fn output_in_out_dir_with_backslashes() {
+        let expected = Path::new("out").join("filtered_game.html");
+        assert_eq!(default_output(Some("out\\"), "game.html"), expected);
+        assert_eq!(default_output(Some("out\\\\"), "game.html"), expected);
+        assert_eq!(default_output(Some("out/\\"), "game.html"), expected);
+    }
+
+    #[test]
+    fn output_in_root_or_working_dir() {
+        assert_eq!(
+            default_output(Some("/"), "game.html"),
+            Path::new("/").join("filtered_game.html")
+        );
+        assert_eq!(
+            default_output(None, "logs/game.html.gz"),
+            Path::new(".").join("filtered_game.html")
+        );
+    }
+
+    #[test]
+    fn existing_path_with_glob_metacharacters_is_literal() {
+        let files = TestFiles::new();
+        let dir = files.path();
+        let log_path = files.write("[round 1] game.html", "");
+
+        assert_eq!(expand_glob(&log_path).unwrap(), vec![log_path.clone()]);
+        assert_eq!(
+            expand_glob(&dir.join("*game.html")).unwrap(),
+            vec![log_path]
+        );
+        assert!(expand_glob(&dir.join("*.txt")).is_err());
+    }
+
+    #[test]
+    fn failed_stream_leaves_no_output() {
+        let files = TestFiles::new();
+        let log_path = files.write(
+            "latin1.html",
+            b"<div class=\"Chat\"><div class=\"ChatMessage\">caf\xe9 nuke</div></div>",
+        );
+        let output_path = files.path().join("out").join("filtered_latin1.html");
+        let config = Config::from_args(ss13_mlogfilter::ConfigArgs {
+            include: vec!["nuke".to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+        let mut options = ProcessOptions::default();
+        options.read.strict = true;
+        options.filter.strict = true;
+
+        let stream = |options| {
+            stream_path(
+                &log_path,
+                &output_path,
+                &config,
+                options,
+                false,
+                &mut StageTimings::default(),
+            )
+        };
+        assert!(stream(options).is_err());
+        assert_eq!(output_path.parent().unwrap().read_dir().unwrap().count(), 0);
+
+        // nor does it block the next run
+        assert!(stream(ProcessOptions::default()).is_ok());
+        assert!(
+            std::fs::read_to_string(&output_path)
+                .unwrap()
+                .contains("caf\u{fffd} nuke")
+        );
+        assert_eq!(output_path.parent().unwrap().read_dir().unwrap().count(), 1);
+    }
+
+    #[test]
+    fn plain_and_gzipped_logs_are_discovered() {
+        let files = TestFiles::new();
+        let dir = files.path();
+        let log = b"<div class=\"Chat\"><div class=\"ChatMessage\">nuke</div></div>";
+        files.write(Path::new("r1").join("game.html"), log);
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(log).unwrap();
+        files.write(
+            Path::new("r2").join("game.html.gz"),
+            encoder.finish().unwrap(),
+        );
+        files.write(Path::new("r2").join("notes.txt.gz"), "");
+
+        let file_glob = Pattern::new("*.html").unwrap();
+        let logs = discover_logs(dir, &file_glob, |err| panic!("{}", err));
+        assert_eq!(
+            logs,
+            vec![
+                (dir.join("r1").join("game.html"), PathBuf::from("r1")),
+                (dir.join("r2").join("game.html.gz"), PathBuf::from("r2")),
+            ]
+        );
+        for (log_path, _) in logs {
+            let decoded = read_chat_log(&log_path, ReadOptions::default()).unwrap();
+            assert_eq!(decoded.chat_log.as_bytes(), log);
         }
     }
 
-    output.push_str("</div>\n</body>\n</html>");
+    #[test]
+    fn index_suffix_goes_before_extensions() {
+        assert_eq!(
+            with_index_suffix(Path::new("out/filtered_game.html"), 1),
+            Path::new("out/filtered_game_1.html")
+        );
+        assert_eq!(
+            with_index_suffix(Path::new("out/filtered_game.html.gz"), 2),
+            Path::new("out/filtered_game_2.html.gz")
+        );
+        assert_eq!(
+            with_index_suffix(Path::new("filtered_game"), 1),
+            Path::new("filtered_game_1")
+        );
+    }
+
+    #[test]
+    fn part_suffix_goes_before_extensions() {
+        assert_eq!(
+            with_part_suffix(Path::new("out/filtered_game.html"), 1),
+            Path::new("out/filtered_game.part001.html")
+        );
+        assert_eq!(
+            with_part_suffix(Path::new("filtered_game.html.gz"), 12),
+            Path::new("filtered_game.part012.html.gz")
+        );
+    }
 
-    Ok(output)
+    #[test]
+    fn format_extension_replaces_the_output_extension() {
+        assert_eq!(
+            with_format_extension(Path::new("out/filtered_game.html"), OutputFormat::Json),
+            Path::new("out/filtered_game.json")
+        );
+        assert_eq!(
+            with_format_extension(Path::new("filtered_game.html.gz"), OutputFormat::Plain),
+            Path::new("filtered_game.txt.gz")
+        );
+        assert_eq!(
+            with_format_extension(Path::new("filtered_game"), OutputFormat::Jsonl),
+            Path::new("filtered_game.jsonl")
+        );
+    }
+
+    #[test]
+    fn merge_index_lists_sources_as_csv_or_json() {
+        let files = TestFiles::new();
+        let kept_sources = [(Path::new("a.html"), 0), (Path::new("b, \"c\".html"), 4)];
+        let csv_path = files.path().join("index.csv");
+        write_merge_index(&csv_path, &kept_sources, false).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&csv_path).unwrap(),
+            "message,file,index\n1,a.html,1\n2,\"b, \"\"c\"\".html\",5\n"
+        );
+
+        let json_path = files.path().join("index.json");
+        write_merge_index(&json_path, &kept_sources, false).unwrap();
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&json_path).unwrap()).unwrap();
+        assert_eq!(
+            json[1],
+            serde_json::json!({"message": 2, "file": "b, \"c\".html", "index": 5})
+        );
+    }
 }
@@ -0,0 +1,236 @@
+use std::fmt::Debug;
+
+use clap::ValueEnum;
+
+use crate::{CHAT_OPENING, ChatMessage, MESSAGE_MARKER, parsing};
+
+/// Opening tag of the chat pane of logs saved by goonchat
+const GOONCHAT_OPENING: &str = "<div id=\"messages\">";
+
+/// Beginning of the opening tag of every chat message of logs saved by goonchat
+const GOONCHAT_MESSAGE_MARKER: &str = "<div class=\"entry";
+
+/// Chat log split by a `MessageParser`
+#[derive(Debug, Default)]
+pub struct LogParts<'a> {
+    /// Markup before the first chat pane, including its opening tag, or `None` if the log has no panes and the whole
+    /// document is filtered as one
+    pub header: Option<&'a str>,
+    /// Contents of the chat panes in order. Markup between panes, including their opening tags, goes between their
+    /// messages.
+    pub segments: Vec<Segment<'a>>,
+    /// Markup after the last chat pane, starting with its closing tag. Empty if the last pane isn't closed.
+    pub footer: &'a str,
+}
+
+/// Part of the chat log contents
+#[derive(Debug, Clone, Copy)]
+pub enum Segment<'a> {
+    /// Markup, which isn't a message and is kept in the HTML output as is
+    Markup(&'a str),
+    /// Message along with its index among all messages of the log, counted from 0
+    Message(usize, &'a str),
+}
+
+/// Structure of the chat logs saved by some SS13 fork: how a log is split into messages, and what the parts of a
+/// message are.
+///
+/// The built-in parsers are the variants of `BuiltinParser`. A library user adds their own by implementing the trait
+/// for a type of theirs and passing a reference to it as `FilterOptions::parser` to `filter_chat_log_with_options`,
+/// `filter_chat_log_in_formats` or `sink::filter_to_sink_with_options`. Concatenating the header, the segments and the
+/// footer returned by `split` should give the log back, since markup is kept as it is found. Logs aren't streamed with
+/// a parser set, since streaming finds messages by the markup of the tg logs.
+///
+/// Patterns, classes, senders and timestamps of the `Config` are still matched against the markup of each message,
+/// while `parse` makes the messages of the JSON outputs and the ones passed to message sinks.
+pub trait MessageParser: Debug + Sync {
+    /// Parses the markup of a single message, as split by `split`
+    fn parse(&self, chunk: &str) -> ChatMessage;
+
+    /// Splits the whole log into its header, messages and the markup between them, and its footer
+    fn split<'a>(&self, log: &'a str) -> anyhow::Result<LogParts<'a>>;
+}
+
+/// Parsers of the chat logs of SS13 forks, which come with the crate
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BuiltinParser {
+    /// Logs saved by tgchat, used by /tg/station and its downstreams: messages are `<div class="ChatMessage">`
+    /// elements of `<div class="Chat">` panes
+    #[default]
+    Tg,
+    /// Logs saved by goonchat, used by Paradise: messages are `<div class="entry">` elements, within a
+    /// `<div id="messages">` pane if there is one
+    Paradise,
+    /// Plain logs with a message on each line, which isn't blank, and no header or footer
+    Generic,
+}
+
+impl MessageParser for BuiltinParser {
+    fn parse(&self, chunk: &str) -> ChatMessage {
+        ChatMessage::parse(chunk)
+    }
+
+    fn split<'a>(&self, log: &'a str) -> anyhow::Result<LogParts<'a>> {
+        Ok(match self {
+            Self::Tg => split_panes(log, CHAT_OPENING, MESSAGE_MARKER),
+            Self::Paradise => split_panes(log, GOONCHAT_OPENING, GOONCHAT_MESSAGE_MARKER),
+            Self::Generic => split_lines(log),
+        })
+    }
+}
+
+/// Splits the chat log into its header, segments of its chat panes and its footer. Each pane is opened with
+/// `pane_opening`, markup between panes is kept as is. If there are no panes, the whole log is treated as one and
+/// `None` is returned as the header. The footer is empty if the last pane isn't closed.
+pub(crate) fn split_panes<'a>(
+    chat_log: &'a str,
+    pane_opening: &'static str,
+    message_marker: &str,
+) -> LogParts<'a> {
+    let mut parts = chat_log.split_inclusive(pane_opening);
+    let first_part = parts.next().unwrap_or_default();
+    let (header, mut panes): (Option<&str>, Vec<&str>) = if first_part.ends_with(pane_opening) {
+        (Some(first_part), parts.collect())
+    } else {
+        (None, vec![first_part])
+    };
+    // every pane but the last ends with the opening tag of the next one
+    if panes.last().is_none_or(|pane| pane.ends_with(pane_opening)) {
+        panes.push("");
+    }
+
+    let mut segments = Vec::new();
+    let mut footer = "";
+    let mut index = 0;
+    for (pane_index, pane) in panes.iter().enumerate() {
+        let contents = pane.strip_suffix(pane_opening).unwrap_or(pane);
+        let (leading_content, mut messages) = split_messages(contents, message_marker);
+        // markup after the pane ends up in its last chunk
+        let (leading_content, trailing_markup) = match messages.pop() {
+            Some(last_message) => {
+                let (last_message, trailing_markup) =
+                    parsing::split_pane_end(last_message, header.is_none());
+                messages.push(last_message);
+                (leading_content, trailing_markup)
+            }
+            None => parsing::split_pane_end(leading_content, header.is_none()),
+        };
+
+        segments.push(Segment::Markup(leading_content));
+        for message in messages {
+            segments.push(Segment::Message(index, message));
+            index += 1;
+        }
+        if pane_index + 1 < panes.len() {
+            segments.push(Segment::Markup(trailing_markup));
+            segments.push(Segment::Markup(pane_opening));
+        } else {
+            footer = trailing_markup;
+        }
+    }
+    LogParts {
+        header,
+        segments,
+        footer,
+    }
+}
+
+/// Splits the chat pane into the content before the first message and the messages. Each message starts with its
+/// opening tag, which begins with `message_marker`, and ends right before the next one.
+fn split_messages<'a>(chat_messages: &'a str, message_marker: &str) -> (&'a str, Vec<&'a str>) {
+    let mut starts: Vec<usize> = chat_messages
+        .match_indices(message_marker)
+        .map(|(start, _)| start)
+        .collect();
+    let leading_content = &chat_messages[..starts.first().copied().unwrap_or(chat_messages.len())];
+
+    starts.push(chat_messages.len());
+    let messages = starts
+        .windows(2)
+        .map(|bounds| &chat_messages[bounds[0]..bounds[1]])
+        .collect();
+
+    (leading_content, messages)
+}
+
+/// Splits the log into lines, each of which is a message along with its line break, unless it is blank
+fn split_lines(log: &str) -> LogParts<'_> {
+    let mut index = 0;
+    let segments = log
+        .split_inclusive('\n')
+        .map(|line| {
+            if line.trim().is_empty() {
+                Segment::Markup(line)
+            } else {
+                index += 1;
+                Segment::Message(index - 1, line)
+            }
+        })
+        .collect();
+    LogParts {
+        header: None,
+        segments,
+        footer: "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Concatenates the parts back into the log
+    fn joined(parts: &LogParts<'_>) -> String {
+        let mut log = parts.header.unwrap_or_default().to_string();
+        for segment in &parts.segments {
+            match segment {
+                Segment::Markup(markup) | Segment::Message(_, markup) => log.push_str(markup),
+            }
+        }
+        log + parts.footer
+    }
+
+    fn messages<'a>(parts: &LogParts<'a>) -> Vec<&'a str> {
+        parts
+            .segments
+            .iter()
+            .filter_map(|segment| match segment {
+                Segment::Markup(_) => None,
+                Segment::Message(_, message) => Some(*message),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn builtin_parsers_split_their_logs() {
+        let paradise = concat!(
+            "<html><body><div id=\"messages\">",
+            "<div class=\"entry\"><span class=\"name\">John</span> says, \"Hi\"</div>\n",
+            "<div class=\"entry\">The shuttle has docked.</div>\n",
+            "</div>\n</body>\n</html>"
+        );
+        let parts = BuiltinParser::Paradise.split(paradise).unwrap();
+        assert_eq!(parts.header, Some("<html><body><div id=\"messages\">"));
+        assert_eq!(
+            messages(&parts),
+            [
+                "<div class=\"entry\"><span class=\"name\">John</span> says, \"Hi\"</div>\n",
+                "<div class=\"entry\">The shuttle has docked.</div>\n"
+            ]
+        );
+        assert_eq!(parts.footer, "</div>\n</body>\n</html>");
+        assert_eq!(joined(&parts), paradise);
+
+        let generic = "[12:00] John: Hi\n\n[12:01] Jane: Hello";
+        let parts = BuiltinParser::Generic.split(generic).unwrap();
+        assert_eq!(parts.header, None);
+        assert_eq!(
+            messages(&parts),
+            ["[12:00] John: Hi\n", "[12:01] Jane: Hello"]
+        );
+        assert_eq!(joined(&parts), generic);
+        assert_eq!(
+            BuiltinParser::Generic.parse("[12:00] John: Hi\n").text,
+            "[12:00] John: Hi"
+        );
+    }
+}
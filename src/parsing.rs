@@ -0,0 +1,218 @@
+use std::{borrow::Cow, ops::Range};
+
+use serde::Serialize;
+
+/// Opening tag of the timestamp of a message
+pub const TIMESTAMP_OPENING: &str = "<span class=\"timestamp\">";
+
+/// Opening and closing tags of the elements, which contain the name of the sender
+const SENDER_TAGS: [(&str, &str); 2] = [("<span class=\"name\">", "</span>"), ("<b>", "</b>")];
+
+/// Tags, which separate the text around them like whitespace does
+const BREAKING_TAGS: [&str; 6] = ["br", "div", "p", "li", "tr", "td"];
+
+/// Chat message, as written to the JSON output and passed to message sinks
+#[derive(Debug, Clone, Serialize)]
+pub struct ChatMessage {
+    /// Original markup of the message
+    pub html: String,
+    /// Visible text of the message
+    pub text: String,
+    /// Classes of the message's opening tag
+    pub classes: Vec<String>,
+}
+
+impl ChatMessage {
+    pub fn parse(html: &str) -> Self {
+        Self {
+            html: html.to_string(),
+            text: plain_text(html),
+            classes: message_classes(html).map(str::to_string).collect(),
+        }
+    }
+}
+
+/// Extracts the classes of the message's opening tag, e.g. "ChatMessage" and "say" from
+/// `<div class="ChatMessage say">`. Yields nothing if the tag has no class attribute.
+pub fn message_classes(message: &str) -> impl Iterator<Item = &str> {
+    let opening_tag = &message[..message.find('>').unwrap_or(message.len())];
+    let classes = ["class=\"", "class='"].iter().find_map(|attribute_start| {
+        let start = opening_tag.find(attribute_start)? + attribute_start.len();
+        let quote = &attribute_start[attribute_start.len() - 1..];
+        let end = start + opening_tag[start..].find(quote)?;
+        Some(&opening_tag[start..end])
+    });
+    classes.unwrap_or_default().split_whitespace()
+}
+
+/// Extracts the name of the player, who sent the message: the bold or name element, which the visible text of the
+/// message starts with, e.g. "John" from `<b>John</b> says, "Hi"`. Returns `None` if the message doesn't start with
+/// such an element, like system and radio messages.
+pub fn message_sender(message: &str) -> Option<String> {
+    let name = plain_text(&message[sender_range(message)?]);
+    (!name.is_empty()).then_some(name)
+}
+
+/// Finds the markup of the name, which `message_sender` extracts the sender from
+pub fn sender_range(message: &str) -> Option<Range<usize>> {
+    let (start, opening, closing) = SENDER_TAGS
+        .iter()
+        .filter_map(|(opening, closing)| Some((message.find(opening)?, *opening, *closing)))
+        .min_by_key(|(start, _, _)| *start)?;
+    // only the timestamp may go before the sender
+    if !plain_text(&without_timestamp(&message[..start])).is_empty() {
+        return None;
+    }
+
+    let name_start = start + opening.len();
+    let name_end = name_start + message[name_start..].find(closing)?;
+    Some(name_start..name_end)
+}
+
+/// Removes the timestamp span of the message, if any, so that messages can be compared regardless of their time
+pub fn without_timestamp(message: &str) -> Cow<'_, str> {
+    let Some(start) = message.find(TIMESTAMP_OPENING) else {
+        return Cow::Borrowed(message);
+    };
+    let end = message[start..]
+        .find("</span>")
+        .map_or(message.len(), |end| start + end + "</span>".len());
+    Cow::Owned(format!("{}{}", &message[..start], &message[end..]))
+}
+
+/// Splits the last chunk of a chat pane into its own markup and the markup after the pane, which starts with the tag
+/// closing the pane: the first `</div>` without a matching opening tag, or `</body>` if the whole document is treated
+/// as a pane. The markup after the pane is empty if it isn't closed.
+pub fn split_pane_end(chunk: &str, whole_document: bool) -> (&str, &str) {
+    let end = if whole_document {
+        chunk.find("</body")
+    } else {
+        let mut depth = 0;
+        chunk.match_indices('<').find_map(|(start, _)| {
+            let tag = &chunk[start..];
+            if is_tag(tag, "<div") {
+                depth += 1;
+            } else if is_tag(tag, "</div") {
+                if depth == 0 {
+                    return Some(start);
+                }
+                depth -= 1;
+            }
+            None
+        })
+    };
+    chunk.split_at(end.unwrap_or(chunk.len()))
+}
+
+/// Counts opening and closing div tags in `html`
+pub fn count_div_tags(html: &str) -> (usize, usize) {
+    html.match_indices('<')
+        .fold((0, 0), |(opening, closing), (start, _)| {
+            let tag = &html[start..];
+            if is_tag(tag, "<div") {
+                (opening + 1, closing)
+            } else if is_tag(tag, "</div") {
+                (opening, closing + 1)
+            } else {
+                (opening, closing)
+            }
+        })
+}
+
+/// Whether `html` starts with the tag beginning with `tag_start`, and not with a tag which name only starts the same
+fn is_tag(html: &str, tag_start: &str) -> bool {
+    html.strip_prefix(tag_start)
+        .is_some_and(|rest| !rest.starts_with(|character: char| character.is_ascii_alphanumeric()))
+}
+
+/// Extracts the visible text of `html` on a single line: tags are removed, entities are decoded and whitespace is
+/// collapsed into single spaces
+pub fn plain_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('>')
+            .map_or(rest.len(), |end| start + end + 1);
+        if is_breaking_tag(&rest[start..end]) {
+            text.push(' ');
+        }
+        rest = &rest[end..];
+    }
+    text.push_str(rest);
+
+    decode_entities(&text)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Escapes the characters, which would be read as markup, so that `text` is shown as is
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+fn is_breaking_tag(tag: &str) -> bool {
+    let name: String = tag
+        .trim_start_matches(['<', '/'])
+        .chars()
+        .take_while(char::is_ascii_alphanumeric)
+        .collect();
+    BREAKING_TAGS
+        .iter()
+        .any(|breaking_tag| name.eq_ignore_ascii_case(breaking_tag))
+}
+
+/// Decodes the named entities common in chat logs and all numeric ones. Unknown entities are left as is.
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let entity = rest
+            .find(';')
+            .and_then(|end| Some((decode_entity(&rest[1..end])?, end)));
+        match entity {
+            Some((character, end)) => {
+                decoded.push(character);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// Decodes the entity name between "&" and ";"
+fn decode_entity(name: &str) -> Option<char> {
+    let code = match name {
+        "amp" => return Some('&'),
+        "lt" => return Some('<'),
+        "gt" => return Some('>'),
+        "quot" => return Some('"'),
+        "apos" => return Some('\''),
+        "nbsp" => return Some('\u{a0}'),
+        _ => match name.strip_prefix('#')? {
+            hex if hex.starts_with(['x', 'X']) => u32::from_str_radix(&hex[1..], 16).ok()?,
+            decimal => decimal.parse().ok()?,
+        },
+    };
+    char::from_u32(code)
+}
@@ -0,0 +1,193 @@
+use std::io::Write;
+
+use crate::{
+    ChatMessage, Config, FilterOptions, FilterStats, OutputFormat, Segment, StageTimings,
+    filter_messages,
+};
+
+/// Destination of the kept messages, which `filter_to_sink` passes them to one by one, e.g. a channel or a database.
+/// Implemented by `WriterSink` for the output formats and by `Vec<ChatMessage>` for collecting the messages.
+pub trait MessageSink {
+    /// What the sink leaves once all messages are written, e.g. the writer they were written to
+    type Output;
+
+    /// Receives the next kept message. An error stops filtering and is returned by `filter_to_sink`.
+    fn write_message(&mut self, message: &ChatMessage) -> anyhow::Result<()>;
+
+    /// Called once after the last kept message
+    fn finish(self) -> anyhow::Result<Self::Output>;
+}
+
+/// Writes the kept messages to `writer` in an output format: their markup, their visible text one per line, an array
+/// of JSON objects or one JSON object per line. Unlike the filtered logs, HTML is written without the header and
+/// footer of the log.
+pub struct WriterSink<W: Write> {
+    writer: W,
+    format: OutputFormat,
+    written: usize,
+}
+
+impl<W: Write> WriterSink<W> {
+    pub fn new(writer: W, format: OutputFormat) -> Self {
+        Self {
+            writer,
+            format,
+            written: 0,
+        }
+    }
+}
+
+impl<W: Write> MessageSink for WriterSink<W> {
+    type Output = W;
+
+    fn write_message(&mut self, message: &ChatMessage) -> anyhow::Result<()> {
+        match self.format {
+            OutputFormat::Html => self.writer.write_all(message.html.as_bytes())?,
+            OutputFormat::Plain => writeln!(self.writer, "{}", message.text)?,
+            OutputFormat::Json => {
+                self.writer
+                    .write_all(if self.written == 0 { b"[" } else { b"," })?;
+                serde_json::to_writer(&mut self.writer, message)?;
+            }
+            OutputFormat::Jsonl => {
+                serde_json::to_writer(&mut self.writer, message)?;
+                self.writer.write_all(b"\n")?;
+            }
+        }
+        self.written += 1;
+        Ok(())
+    }
+
+    fn finish(mut self) -> anyhow::Result<W> {
+        if self.format == OutputFormat::Json {
+            self.writer
+                .write_all(if self.written == 0 { b"[]" } else { b"]" })?;
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl MessageSink for Vec<ChatMessage> {
+    type Output = Self;
+
+    fn write_message(&mut self, message: &ChatMessage) -> anyhow::Result<()> {
+        self.push(message.clone());
+        Ok(())
+    }
+
+    fn finish(self) -> anyhow::Result<Self> {
+        Ok(self)
+    }
+}
+
+/// Filters messages of the chat log with the default options into `sink`. Returns what the sink left along with the
+/// stats.
+pub fn filter_to_sink<S: MessageSink>(
+    chat_log: &str,
+    config: &Config,
+    sink: S,
+) -> anyhow::Result<(S::Output, FilterStats)> {
+    filter_to_sink_with_options(chat_log, config, FilterOptions::default(), sink)
+}
+
+/// Filters messages of the chat log into `sink`, passing it the messages kept by `filter_chat_log_with_options` in
+/// order, as parsed by the parser of the options. Scripts, timestamps and senders are stripped and anonymized as the
+/// options say, while the options of the output document are left to the sink: the format, table of contents,
+/// separators, message template and repeat markers. The output budget is checked against the HTML of the kept
+/// messages, and the stats don't count the output, since the sink decides what is written.
+pub fn filter_to_sink_with_options<S: MessageSink>(
+    chat_log: &str,
+    config: &Config,
+    options: FilterOptions<'_>,
+    mut sink: S,
+) -> anyhow::Result<(S::Output, FilterStats)> {
+    let options = FilterOptions {
+        format: OutputFormat::Html,
+        toc: false,
+        separator: None,
+        message_template: None,
+        mark_repeats: false,
+        ..options
+    };
+    let (_, mut stats, kept_indices) =
+        filter_messages(chat_log, config, options, &mut StageTimings::default())?;
+    stats.bytes_out = 0;
+    stats.lines_out = 0;
+
+    let parser = options.parser();
+    let messages: Vec<&str> = parser
+        .split(chat_log)?
+        .segments
+        .iter()
+        .filter_map(|segment| match segment {
+            Segment::Markup(_) => None,
+            Segment::Message(_, message) => Some(*message),
+        })
+        .collect();
+    for index in kept_indices {
+        sink.write_message(&parser.parse(&options.prepare(messages[index])))?;
+    }
+    Ok((sink.finish()?, stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ConfigArgs, filter_chat_log_with_options};
+
+    #[test]
+    fn writer_sinks_write_like_the_filtered_logs() {
+        let chat_log = concat!(
+            "<html><body><div class=\"Chat\">",
+            "<div class=\"ChatMessage say\"><span class=\"timestamp\">[12:00]</span> <b>John</b> says, ",
+            "\"nuke\"</div>\n",
+            "<div class=\"ChatMessage\">calm</div>\n",
+            "<div class=\"ChatMessage ooc\">nuke\nagain</div>\n",
+            "</div>\n</body>\n</html>"
+        );
+        let config = Config::from_args(ConfigArgs {
+            include: vec!["nuke".to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+        for format in [OutputFormat::Plain, OutputFormat::Json, OutputFormat::Jsonl] {
+            let options = FilterOptions {
+                format,
+                strip_timestamps: true,
+                ..Default::default()
+            };
+            let (written, stats) = filter_to_sink_with_options(
+                chat_log,
+                &config,
+                options,
+                WriterSink::new(Vec::new(), format),
+            )
+            .unwrap();
+            let (filtered, filtered_stats) = filter_chat_log_with_options(
+                chat_log,
+                &config,
+                options,
+                &mut StageTimings::default(),
+            )
+            .unwrap();
+            assert_eq!(
+                String::from_utf8(written).unwrap(),
+                filtered,
+                "{:?}",
+                format
+            );
+            assert_eq!(stats.kept, filtered_stats.kept);
+        }
+
+        let (collected, stats) = filter_to_sink(chat_log, &config, Vec::new()).unwrap();
+        assert_eq!(stats.total, 3);
+        assert_eq!(
+            collected
+                .iter()
+                .map(|message| message.text.as_str())
+                .collect::<Vec<_>>(),
+            ["[12:00] John says, \"nuke\"", "nuke again"]
+        );
+    }
+}
@@ -0,0 +1,594 @@
+use std::{
+    io::{BufRead, BufWriter, Write},
+    mem,
+};
+
+use crate::{
+    CHAT_LOG_FOOTER, CHAT_OPENING, FilterOptions, FilterStats, MESSAGE_MARKER, MessageFilter,
+    OutputFormat, Verdict, config::Config, input::GZIP_MAGIC, parsing,
+};
+
+/// Beginning of the chat log, read before any output is created
+pub struct Header {
+    /// Everything up to and including the opening tag of the first chat pane, or `None` if the log has no panes
+    html: Option<String>,
+    /// Bytes read after the opening tag, or the whole log if it has no panes
+    rest: Vec<u8>,
+    /// Number of bytes read from the input
+    bytes_read: usize,
+    /// Whether the header wasn't valid UTF-8
+    lossy: bool,
+}
+
+/// Reads the chat log up to the opening tag of its first chat pane. If the log has no panes, it is read whole, since
+/// it is filtered as one pane.
+pub fn read_header(reader: &mut impl BufRead) -> anyhow::Result<Header> {
+    let mut chunks = ChunkReader::new(reader, Vec::new(), 0);
+    let (html, lossy) = match chunks.find(0, &[CHAT_OPENING])? {
+        Some((start, _)) => {
+            let (html, lossy) = chunks.take(start + CHAT_OPENING.len());
+            (Some(html), lossy)
+        }
+        None => (None, false),
+    };
+    Ok(Header {
+        html,
+        rest: chunks.buffer,
+        bytes_read: chunks.bytes_read,
+        lossy,
+    })
+}
+
+/// Filters the rest of the chat log after `header` from `reader` into `writer` message by message, holding only the
+/// current message in memory. Reading stops once the limit of kept messages is reached, so the stats count only the
+/// part of the log read by then. Window matching and the table of contents need the whole log, so they aren't
+/// supported. Messages, which aren't valid UTF-8, are decoded lossily, unless the mode is strict, in which case they
+/// fail the log.
+pub fn stream_chat_log(
+    reader: impl BufRead,
+    header: Header,
+    writer: impl Write,
+    config: &Config,
+    options: FilterOptions<'_>,
+) -> anyhow::Result<FilterStats> {
+    let whole_document = header.html.is_none();
+    let mut output = StreamOutput::new(
+        BufWriter::new(writer),
+        config,
+        options,
+        FilterStats {
+            whole_document,
+            lossy: header.lossy,
+            ..Default::default()
+        },
+    );
+    output.check_lossy()?;
+    if output.is_html() {
+        output.write(&options.sanitize(&header.html.unwrap_or_default()))?;
+    }
+
+    let mut chunks = ChunkReader::new(reader, header.rest, header.bytes_read);
+    let mut position = Position::default();
+    output.filter_chunks(&mut chunks, &mut position)?;
+    if output.is_full() {
+        // the rest of the log isn't read, so it is closed like a cut off one
+        output.filter.stats.limit_reached = true;
+        output.filter.stats.read_partially = true;
+        if output.is_html() && !whole_document {
+            output.write(CHAT_LOG_FOOTER)?;
+        }
+    } else {
+        let rest_len = chunks.buffer.len();
+        let chunk = output.take(&mut chunks, rest_len)?;
+        let (last_chunk, mut log_footer) = parsing::split_pane_end(&chunk, whole_document);
+        output.push(last_chunk, position.is_message)?;
+        // the log may be cut off, e.g. if it is still being written
+        if log_footer.is_empty() && !whole_document {
+            log_footer = CHAT_LOG_FOOTER;
+        }
+        if output.is_html() {
+            output.write(log_footer)?;
+        }
+    }
+
+    output
+        .writer
+        .flush()
+        .map_err(|err| anyhow::format_err!("error while writing the output: {}", err))?;
+    output.filter.stats.bytes_in = chunks.bytes_read;
+    Ok(output.filter.stats)
+}
+
+/// Filters a chat log, which is still being written, as it grows, like `tail -f`: every piece appended to the log is
+/// filtered as soon as the messages in it are complete. A message is complete once the next one starts or the log is
+/// closed by its footer, so the last message is held back until then. Supports the same options as `stream_chat_log`,
+/// but only logs with chat panes, and nothing is written until the header is complete.
+pub struct LogFollower<'a> {
+    output: StreamOutput<'a, Vec<u8>>,
+    /// Bytes read, but not filtered yet: the header until it is complete, then the last message
+    buffer: Vec<u8>,
+    bytes_read: usize,
+    position: Position,
+    header_written: bool,
+    /// Whether the log was closed by its footer
+    finished: bool,
+}
+
+impl<'a> LogFollower<'a> {
+    pub fn new(config: &'a Config, options: FilterOptions<'a>) -> Self {
+        Self {
+            output: StreamOutput::new(Vec::new(), config, options, FilterStats::default()),
+            buffer: Vec::new(),
+            bytes_read: 0,
+            position: Position::default(),
+            header_written: false,
+            finished: false,
+        }
+    }
+
+    /// Filters `input`, which was appended to the log since the last call. Returns what has to be appended to the
+    /// output: the header once it is complete, then the kept messages completed by `input`, along with the markup
+    /// between them. The footer is up to the caller, see `footer`.
+    pub fn push(&mut self, input: &[u8]) -> anyhow::Result<Vec<u8>> {
+        if self.bytes_read == 0 && input.starts_with(&GZIP_MAGIC) {
+            Err(anyhow::Error::msg("gzipped logs can't be followed"))?
+        }
+        if self.is_done() {
+            return Ok(Vec::new());
+        }
+
+        let mut chunks = ChunkReader::new(input, mem::take(&mut self.buffer), self.bytes_read);
+        if !self.header_written {
+            let Some((start, _)) = chunks.find(0, &[CHAT_OPENING])? else {
+                self.buffer = chunks.buffer;
+                self.bytes_read = chunks.bytes_read;
+                return Ok(Vec::new());
+            };
+            let html = self.output.take(&mut chunks, start + CHAT_OPENING.len())?;
+            if self.output.is_html() {
+                self.output
+                    .write(&self.output.filter.options.sanitize(&html))?;
+            }
+            self.header_written = true;
+        }
+
+        self.output.filter_chunks(&mut chunks, &mut self.position)?;
+        if self.output.is_full() {
+            self.output.filter.stats.limit_reached = true;
+            self.output.filter.stats.read_partially = true;
+        } else if contains(&chunks.buffer, b"</html") {
+            // nothing is written after the footer, so the last message is complete
+            let rest_len = chunks.buffer.len();
+            let chunk = self.output.take(&mut chunks, rest_len)?;
+            let (last_chunk, _) = parsing::split_pane_end(&chunk, false);
+            self.output.push(last_chunk, self.position.is_message)?;
+            self.finished = true;
+        }
+        self.buffer = chunks.buffer;
+        self.bytes_read = chunks.bytes_read;
+        self.output.filter.stats.bytes_in = self.bytes_read;
+        Ok(mem::take(&mut self.output.writer))
+    }
+
+    /// Whether there is nothing left to follow, since the log was closed by its footer or the limit of kept messages
+    /// was reached
+    pub fn is_done(&self) -> bool {
+        self.finished || self.output.is_full()
+    }
+
+    /// Markup, which closes the output after the part of it returned so far
+    pub fn footer(&self) -> &'static str {
+        if self.output.is_html() && self.header_written {
+            CHAT_LOG_FOOTER
+        } else {
+            ""
+        }
+    }
+
+    pub fn stats(&self) -> &FilterStats {
+        &self.output.filter.stats
+    }
+}
+
+/// Position in the chat log after the last chunk filtered
+#[derive(Debug, Default)]
+struct Position {
+    /// Whether the chunk in the buffer is a message. Markup before the first message of a pane isn't one.
+    is_message: bool,
+    /// Position in the buffer to search for the next tag from
+    from: usize,
+}
+
+/// Whether `haystack` contains `needle`
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+/// Reader of the chat log, which buffers it only up to the next tag searched for
+struct ChunkReader<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    /// Number of bytes read from the input
+    bytes_read: usize,
+}
+
+impl<R: BufRead> ChunkReader<R> {
+    fn new(reader: R, buffer: Vec<u8>, bytes_read: usize) -> Self {
+        Self {
+            reader,
+            buffer,
+            bytes_read,
+        }
+    }
+
+    /// Reads until the buffer contains any of `tags` at `from` or later. Returns the position of the first tag found
+    /// along with the tag, or `None` if the input ends without any of them.
+    fn find(
+        &mut self,
+        from: usize,
+        tags: &[&'static str],
+    ) -> anyhow::Result<Option<(usize, &'static str)>> {
+        let longest_tag = tags.iter().map(|tag| tag.len()).max().unwrap_or_default();
+        let mut searched = from;
+        loop {
+            let found = tags
+                .iter()
+                .filter_map(|tag| {
+                    let start = self
+                        .buffer
+                        .get(searched..)?
+                        .windows(tag.len())
+                        .position(|window| window == tag.as_bytes())?;
+                    Some((searched + start, *tag))
+                })
+                .min_by_key(|(start, _)| *start);
+            if found.is_some() {
+                return Ok(found);
+            }
+            // a tag may begin at the end of the buffer and continue in the next read
+            searched = self
+                .buffer
+                .len()
+                .saturating_sub(longest_tag.saturating_sub(1))
+                .max(from);
+
+            let read = self.reader.fill_buf().map_err(|err| {
+                anyhow::format_err!("error while reading the input file: {}", err)
+            })?;
+            if read.is_empty() {
+                return Ok(None);
+            }
+            let read_len = read.len();
+            self.buffer.extend_from_slice(read);
+            self.reader.consume(read_len);
+            self.bytes_read += read_len;
+        }
+    }
+
+    /// Removes the first `len` bytes from the buffer, replacing invalid UTF-8 with replacement characters. Returns the
+    /// decoded bytes and whether any of them were invalid. Tags are ASCII, so the buffer is never split within a
+    /// character.
+    fn take(&mut self, len: usize) -> (String, bool) {
+        let bytes: Vec<u8> = self.buffer.drain(..len).collect();
+        match String::from_utf8(bytes) {
+            Ok(text) => (text, false),
+            Err(err) => (String::from_utf8_lossy(err.as_bytes()).into_owned(), true),
+        }
+    }
+}
+
+/// Output of a streamed log along with the state of filtering it
+struct StreamOutput<'a, W: Write> {
+    writer: W,
+    config: &'a Config,
+    filter: MessageFilter<'a>,
+}
+
+impl<'a, W: Write> StreamOutput<'a, W> {
+    fn new(writer: W, config: &'a Config, options: FilterOptions<'a>, stats: FilterStats) -> Self {
+        let footer_len = if options.format == OutputFormat::Html {
+            // the actual footer of the log isn't known until it is read, so the usual one is assumed
+            CHAT_LOG_FOOTER.len()
+        } else {
+            0
+        };
+        Self {
+            writer,
+            config,
+            filter: MessageFilter::new(options, footer_len, stats),
+        }
+    }
+
+    /// Filters the chunks of the log from `chunks`, starting at `position`, until the input ends or the limit of kept
+    /// messages is reached. The last chunk is left in the buffer, since it may go on in the rest of the log.
+    fn filter_chunks<R: BufRead>(
+        &mut self,
+        chunks: &mut ChunkReader<R>,
+        position: &mut Position,
+    ) -> anyhow::Result<()> {
+        while !self.is_full()
+            && let Some((start, tag)) =
+                chunks.find(position.from, &[MESSAGE_MARKER, CHAT_OPENING])?
+        {
+            let chunk = self.take(chunks, start)?;
+            if tag == CHAT_OPENING {
+                // markup between panes is kept as is
+                let (last_chunk, trailing_markup) = parsing::split_pane_end(&chunk, false);
+                self.push(last_chunk, position.is_message)?;
+                if self.is_full() {
+                    break;
+                }
+                self.push(trailing_markup, false)?;
+                self.push(CHAT_OPENING, false)?;
+                chunks.take(CHAT_OPENING.len());
+                position.is_message = false;
+                position.from = 0;
+            } else {
+                self.push(&chunk, position.is_message)?;
+                position.is_message = true;
+                // the opening tag stays in the buffer as the start of the next message
+                position.from = MESSAGE_MARKER.len();
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `chunk` if it is a message which should be kept, or if it isn't a message at all
+    fn push(&mut self, chunk: &str, is_message: bool) -> anyhow::Result<()> {
+        if !is_message {
+            if self.is_html() {
+                self.write(&self.filter.options.sanitize(chunk))?;
+            }
+            return Ok(());
+        }
+        let index = self.filter.stats.total;
+        self.filter.stats.total += 1;
+
+        let config = self.config;
+        match self.filter.check(index, chunk, || config.matches(chunk))? {
+            Verdict::Stop | Verdict::Drop => return Ok(()),
+            Verdict::Match => {}
+        }
+        let written = self.filter.stats.bytes_out;
+        if let Some(kept) = self.filter.keep(index, chunk, written)? {
+            self.write(kept.separator)?;
+            self.write(&kept.message)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the limit of kept messages is reached, so the rest of the log doesn't have to be read
+    fn is_full(&self) -> bool {
+        self.filter.is_full()
+    }
+
+    /// Takes the next `len` bytes from `chunks`, failing if they aren't valid UTF-8 in strict mode
+    fn take<R: BufRead>(
+        &mut self,
+        chunks: &mut ChunkReader<R>,
+        len: usize,
+    ) -> anyhow::Result<String> {
+        let (chunk, lossy) = chunks.take(len);
+        self.filter.stats.lossy |= lossy;
+        self.check_lossy()?;
+        Ok(chunk)
+    }
+
+    /// Fails if invalid UTF-8 was read in strict mode
+    fn check_lossy(&self) -> anyhow::Result<()> {
+        if self.filter.stats.lossy && self.filter.options.strict {
+            Err(anyhow::format_err!(
+                "error while reading the input file: stream did not contain valid UTF-8"
+            ))?
+        }
+        Ok(())
+    }
+
+    fn is_html(&self) -> bool {
+        self.filter.options.format == OutputFormat::Html
+    }
+
+    fn write(&mut self, html: &str) -> anyhow::Result<()> {
+        self.writer
+            .write_all(html.as_bytes())
+            .map_err(|err| anyhow::format_err!("error while writing the output: {}", err))?;
+        self.filter.stats.bytes_out += html.len();
+        self.filter.stats.lines_out += html.matches('\n').count();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+
+    use super::*;
+    use crate::{ConfigArgs, StageTimings, filter_chat_log_with_options};
+
+    /// Log with all messages on a single line, two panes and markup before the first message
+    const CHAT_LOG: &str = concat!(
+        "<html><head><script>x()</script></head><body><div class=\"Chat\"><h1>Round 42</h1>",
+        "<div class=\"ChatMessage say\"><b>John</b> says, \"nuke <i>armed</i>\"</div>",
+        "<div class=\"ChatMessage\">all clear<script>y()</script></div>",
+        "<div class=\"ChatMessage\">nuke disarmed</div>",
+        "</div><p>between panes</p><div class=\"Chat\">",
+        "<div class=\"ChatMessage\">café nuke</div>",
+        "<div class=\"ChatMessage\">calm</div>",
+        "</div>\n</body>\n</html>"
+    );
+
+    fn config() -> Config {
+        Config::from_args(ConfigArgs {
+            include: vec!["nuke".to_string()],
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    /// Streams `chat_log` through a reader with a tiny buffer, so that tags are split between reads
+    fn streamed(chat_log: &[u8], options: FilterOptions<'_>) -> (String, FilterStats) {
+        let mut reader = BufReader::with_capacity(5, chat_log);
+        let header = read_header(&mut reader).unwrap();
+        let mut output = Vec::new();
+        let stats = stream_chat_log(reader, header, &mut output, &config(), options).unwrap();
+        (String::from_utf8(output).unwrap(), stats)
+    }
+
+    fn assert_same_as_in_memory(chat_log: &str, options: FilterOptions<'_>) {
+        let (expected, expected_stats) = filter_chat_log_with_options(
+            chat_log,
+            &config(),
+            options,
+            &mut StageTimings::default(),
+        )
+        .unwrap();
+        let (output, stats) = streamed(chat_log.as_bytes(), options);
+        assert_eq!(output, expected, "{:?}", options);
+        assert_eq!(stats.kept, expected_stats.kept);
+        assert_eq!(stats.omitted_by_budget, expected_stats.omitted_by_budget);
+        assert_eq!(stats.bytes_out, output.len());
+        assert_eq!(stats.lines_out, expected_stats.lines_out);
+        // the rest of the log isn't read after the limit
+        if !stats.read_partially {
+            assert_eq!(stats.total, expected_stats.total);
+            assert_eq!(stats.bytes_in, chat_log.len());
+        }
+    }
+
+    #[test]
+    fn streamed_output_matches_in_memory_output() {
+        let option_sets = [
+            FilterOptions::default(),
+            FilterOptions {
+                limit: Some(2),
+                ..Default::default()
+            },
+            FilterOptions {
+                separator: Some("<hr>"),
+                strip_scripts: true,
+                ..Default::default()
+            },
+            FilterOptions {
+                max_message_length: Some(50),
+                ..Default::default()
+            },
+            FilterOptions {
+                output_budget: Some(250),
+                ..Default::default()
+            },
+            FilterOptions {
+                format: OutputFormat::Plain,
+                ..Default::default()
+            },
+            FilterOptions {
+                format: OutputFormat::Jsonl,
+                output_budget: Some(200),
+                ..Default::default()
+            },
+        ];
+        for options in option_sets {
+            assert_same_as_in_memory(CHAT_LOG, options);
+        }
+    }
+
+    #[test]
+    fn synthetic_log_streams_like_in_memory() {
+        let chat_log = crate::synthetic::chat_log(500, 7);
+        for options in [
+            FilterOptions::default(),
+            FilterOptions {
+                limit: Some(20),
+                format: OutputFormat::Jsonl,
+                strip_timestamps: true,
+                ..Default::default()
+            },
+        ] {
+            assert_same_as_in_memory(&chat_log, options);
+        }
+    }
+
+    #[test]
+    fn reading_stops_at_the_limit() {
+        let options = FilterOptions {
+            limit: Some(1),
+            ..Default::default()
+        };
+        let (output, stats) = streamed(CHAT_LOG.as_bytes(), options);
+        assert!(output.ends_with("\"nuke <i>armed</i>\"</div></div>\n</body>\n</html>"));
+        assert_eq!(stats.kept, 1);
+        assert_eq!(stats.total, 1);
+        assert!(stats.limit_reached && stats.read_partially);
+        assert!(stats.bytes_in < CHAT_LOG.len());
+    }
+
+    #[test]
+    fn followed_log_matches_streamed_log() {
+        let options = FilterOptions {
+            separator: Some("<hr>"),
+            ..Default::default()
+        };
+        let config = config();
+        let mut follower = LogFollower::new(&config, options);
+        let mut output = Vec::new();
+        let pieces: Vec<&[u8]> = CHAT_LOG.as_bytes().chunks(7).collect();
+        for (index, piece) in pieces.iter().enumerate() {
+            output.extend(follower.push(piece).unwrap());
+            // the log is followed until its footer is complete
+            assert_eq!(follower.is_done(), index + 1 == pieces.len());
+        }
+        output.extend(follower.footer().as_bytes());
+
+        let (expected, stats) = streamed(CHAT_LOG.as_bytes(), options);
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+        assert_eq!(follower.stats().kept, stats.kept);
+        assert_eq!(follower.stats().total, stats.total);
+    }
+
+    #[test]
+    fn logs_without_panes_or_footer_are_streamed() {
+        assert_same_as_in_memory(
+            "<html><body><div class=\"ChatMessage\">nuke</div><div class=\"ChatMessage\">calm</div></body></html>",
+            FilterOptions::default(),
+        );
+        assert_same_as_in_memory(
+            "<html><body><div class=\"Chat\"><div class=\"ChatMessage\">nuke</div><div class=\"ChatMessage\">ca",
+            FilterOptions::default(),
+        );
+    }
+
+    #[test]
+    fn invalid_utf8_is_lossy_unless_strict() {
+        let chat_log = b"<div class=\"Chat\"><div class=\"ChatMessage\">caf\xe9 nuke</div></div>";
+        let (output, stats) = streamed(chat_log, FilterOptions::default());
+        assert!(output.contains("caf\u{fffd} nuke"));
+        assert!(stats.lossy);
+
+        let mut reader = BufReader::new(&chat_log[..]);
+        let header = read_header(&mut reader).unwrap();
+        let strict = FilterOptions {
+            strict: true,
+            ..Default::default()
+        };
+        assert!(stream_chat_log(reader, header, Vec::new(), &config(), strict).is_err());
+    }
+
+    #[test]
+    fn single_line_log_is_buffered_message_by_message() {
+        let message = "<div class=\"ChatMessage\">nuke</div>";
+        let chat_log = message.repeat(1000);
+        let mut chunks = ChunkReader::new(
+            BufReader::with_capacity(64, chat_log.as_bytes()),
+            Vec::new(),
+            0,
+        );
+        let mut from = 0;
+        while let Some((start, _)) = chunks.find(from, &[MESSAGE_MARKER]).unwrap() {
+            assert!(chunks.buffer.len() <= 2 * message.len() + 64);
+            chunks.take(start);
+            from = MESSAGE_MARKER.len();
+        }
+        assert_eq!(chunks.bytes_read, chat_log.len());
+    }
+}
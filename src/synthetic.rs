@@ -0,0 +1,115 @@
+use crate::{CHAT_LOG_FOOTER, CHAT_OPENING};
+
+/// Markup before the chat pane of a synthetic log
+const HEADER: &str =
+    "<!DOCTYPE html>\n<html><head><meta charset=\"UTF-8\"><title>Chat Log</title></head><body>";
+
+const SENDERS: [&str; 8] = [
+    "John Doe",
+    "Jane Smith",
+    "Urist McRobust",
+    "Pun Pun",
+    "Captain Bob",
+    "Cargo Technician",
+    "AI",
+    "Ian",
+];
+
+/// Phrases said by the senders, some of which mention the usual emergencies
+const PHRASES: [&str; 12] = [
+    "the nuke is armed",
+    "hull breach in medbay",
+    "singularity is loose, evacuate",
+    "can someone open the bridge?",
+    "who took my toolbox",
+    "cargo, order more plasma",
+    "all clear on the east wing",
+    "traitor in security!",
+    "calling the shuttle",
+    "need a doctor at the bar",
+    "café is open for business",
+    "lol",
+];
+
+/// Actions of emotes
+const ACTIONS: [&str; 4] = ["nods", "waves", "screams", "laughs"];
+
+/// Deterministic xorshift generator, so the same seed always produces the same log
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // the state must not be zero
+        Self(seed ^ 0x9e37_79b9_7f4a_7c15)
+    }
+
+    /// Returns a number below `bound`
+    fn below(&mut self, bound: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % bound as u64) as usize
+    }
+
+    fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+        items[self.below(items.len())]
+    }
+}
+
+/// Generates `count` messages of different kinds: speech, radio, OOC, emotes and warnings, each with a timestamp a few
+/// seconds after the previous one. Messages with the same `seed` are the same.
+pub fn chat_messages(count: usize, seed: u64) -> Vec<String> {
+    let mut rng = Rng::new(seed);
+    let mut seconds = 12 * 60 * 60;
+    (0..count)
+        .map(|_| {
+            seconds += rng.below(5);
+            let timestamp = format!(
+                "<span class=\"timestamp\">[{:02}:{:02}:{:02}]</span>",
+                seconds / 3600 % 24,
+                seconds / 60 % 60,
+                seconds % 60
+            );
+            let sender = rng.pick(&SENDERS);
+            let phrase = rng.pick(&PHRASES);
+            match rng.below(10) {
+                0..=3 => format!(
+                    "<div class=\"ChatMessage say\">{} <span class=\"name\">{}</span> says, \"{}\"</div>\n",
+                    timestamp, sender, phrase
+                ),
+                4..=5 => format!(
+                    "<div class=\"ChatMessage radio\">{} [Common] <span class=\"name\">{}</span> says, \"{}\"</div>\n",
+                    timestamp, sender, phrase
+                ),
+                6..=7 => format!(
+                    "<div class=\"ChatMessage ooc\">{} <span class=\"prefix\">OOC:</span> <b>{}</b>: {}</div>\n",
+                    timestamp, sender, phrase
+                ),
+                8 => format!(
+                    "<div class=\"ChatMessage emote\">{} <b>{}</b> {}.</div>\n",
+                    timestamp,
+                    sender,
+                    rng.pick(&ACTIONS)
+                ),
+                _ => format!(
+                    "<div class=\"ChatMessage warning\">{} <span class=\"warning\">{}!</span></div>\n",
+                    timestamp, phrase
+                ),
+            }
+        })
+        .collect()
+}
+
+/// Generates a whole chat log of `count` messages made by `chat_messages` in a single chat pane
+pub fn chat_log(count: usize, seed: u64) -> String {
+    let messages = chat_messages(count, seed);
+    let mut chat_log = String::with_capacity(messages.iter().map(String::len).sum::<usize>() + 256);
+    chat_log.push_str(HEADER);
+    chat_log.push_str(CHAT_OPENING);
+    chat_log.push('\n');
+    messages
+        .iter()
+        .for_each(|message| chat_log.push_str(message));
+    chat_log.push_str(CHAT_LOG_FOOTER);
+    chat_log
+}
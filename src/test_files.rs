@@ -0,0 +1,36 @@
+//! Files written by tests, shared by the tests of the library and of the CLI
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use tempfile::TempDir;
+
+/// Temporary directory with the files of a single test, removed along with them once dropped
+pub struct TestFiles {
+    dir: TempDir,
+}
+
+impl TestFiles {
+    pub fn new() -> Self {
+        Self {
+            dir: tempfile::tempdir().unwrap(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Writes `contents` into the file at `name` relative to the directory, creating missing subdirectories. Returns
+    /// the path of the file.
+    pub fn write(&self, name: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> PathBuf {
+        let path = self.dir.path().join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::write(&path, contents).unwrap();
+        path
+    }
+}
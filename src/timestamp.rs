@@ -0,0 +1,96 @@
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::parsing::{TIMESTAMP_OPENING, plain_text};
+
+/// Time of the day a message was sent at, with a precision of seconds. Written as "HH:MM" or "HH:MM:SS", e.g.
+/// "12:05" or "12:05:30". Logs don't record dates, so timestamps of a round going past midnight wrap around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Timestamp {
+    /// Seconds since midnight
+    seconds: u32,
+}
+
+impl FromStr for Timestamp {
+    type Err = String;
+
+    fn from_str(timestamp: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("{} is not a time in HH:MM or HH:MM:SS format", timestamp);
+        let parts = timestamp
+            .trim()
+            .split(':')
+            .map(|part| {
+                // "12:5" is as ambiguous as it is unusual
+                if part.len() != 2 {
+                    return None;
+                }
+                part.parse::<u32>().ok()
+            })
+            .collect::<Option<Vec<u32>>>()
+            .ok_or_else(invalid)?;
+        let (hours, minutes, seconds) = match parts[..] {
+            [hours, minutes] => (hours, minutes, 0),
+            [hours, minutes, seconds] => (hours, minutes, seconds),
+            _ => return Err(invalid()),
+        };
+        if hours > 23 || minutes > 59 || seconds > 59 {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            seconds: hours * 3600 + minutes * 60 + seconds,
+        })
+    }
+}
+
+impl TryFrom<String> for Timestamp {
+    type Error = String;
+
+    fn try_from(timestamp: String) -> Result<Self, Self::Error> {
+        timestamp.parse()
+    }
+}
+
+impl From<Timestamp> for String {
+    fn from(timestamp: Timestamp) -> Self {
+        timestamp.to_string()
+    }
+}
+
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}:{:02}",
+            self.seconds / 3600,
+            self.seconds / 60 % 60,
+            self.seconds % 60
+        )
+    }
+}
+
+impl Timestamp {
+    /// Seconds since midnight
+    pub(crate) fn seconds(self) -> u32 {
+        self.seconds
+    }
+}
+
+/// Parses the timestamp of the message out of its timestamp span, e.g. 12:05:30 from
+/// `<span class="timestamp">[12:05:30]</span>`. Brackets and whitespace around the time are ignored. Returns `None`
+/// if the message has no timestamp span, or if the span doesn't contain a time.
+pub fn message_timestamp(message: &str) -> Option<Timestamp> {
+    let start = message.find(TIMESTAMP_OPENING)? + TIMESTAMP_OPENING.len();
+    let end = start + message[start..].find("</span>")?;
+    plain_text(&message[start..end])
+        .trim_matches(|character: char| {
+            character == '[' || character == ']' || character.is_whitespace()
+        })
+        .parse()
+        .ok()
+}